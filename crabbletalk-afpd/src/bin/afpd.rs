@@ -4,35 +4,56 @@
 
 use std::path::PathBuf;
 
-use anyhow::{anyhow, Context, Result};
-use pnet::packet::Packet;
+use anyhow::Result;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    console_subscriber::init();
+    crabbletalk_afpd::init_tracing(true);
     let args: Vec<_> = std::env::args().collect();
     let router_path: PathBuf = args[1].parse()?;
+    let state_file: Option<PathBuf> = args.get(2).map(|a| a.parse()).transpose()?;
     let (sock, _unlinker) = crabbletalk_afpd::anonymous_datagram_client("crabbletalk_afpd", None)?;
     let sock = tokio::net::UnixDatagram::from_std(sock)?;
     sock.connect(&router_path)?;
     sock.send(b"").await?;
     let mut buf = vec![0u8; 1600];
     let mac = crabbletalk::addr::Mac::new_random();
-    println!("afpd starting up on {:?}", mac);
-    let (mut aarp_stack, mut atalk_rx) = crabbletalk::aarp::AarpStackHandle::spawn(mac);
+    tracing::info!(?mac, "afpd starting up");
+    let mut config = crabbletalk::aarp::StackConfig::new(mac)
+        .node_range(crabbletalk::addr::APPLETALK_SERVER_NODE_RANGE);
+    if let Some(state_file) = &state_file {
+        config = config.address_hint(crabbletalk_afpd::load_hint(state_file));
+    }
+    let (aarp_stack, mut atalk_rx) = crabbletalk::aarp::AarpStackHandle::spawn(config)?;
+    let mut tasks = tokio::task::JoinSet::new();
+    tasks.spawn(crabbletalk_afpd::report_stats(aarp_stack.clone()));
+    if let Some(state_file) = state_file {
+        tasks.spawn(crabbletalk_afpd::persist_addresses(
+            aarp_stack.clone(),
+            state_file,
+        ));
+    }
 
     loop {
-        let (n_read, addr) = tokio::select! {
+        let (n_read, _addr) = tokio::select! {
             _ = tokio::signal::ctrl_c() => { break }
+            joined = tasks.join_next(), if !tasks.is_empty() => {
+                match joined {
+                    Some(Ok(Ok(()))) | None => tracing::debug!("task finished"),
+                    Some(Ok(Err(e))) => tracing::warn!(error = ?e, "task failed"),
+                    Some(Err(e)) => tracing::warn!(error = ?e, "task panicked"),
+                }
+                continue
+            }
             r = sock.recv_from(&mut buf) => { r }
             atalk = atalk_rx.recv() => {
                 match &atalk {
                     Some(p) => {
-                        println!("oh no {:?}", p);
+                        tracing::trace!(?p, "to the router");
                         sock.send(&p.0[..]).await?;
                     }
                     None => {
-                        println!("oh no bye??");
+                        tracing::info!("stack went away");
                         break;
                     }
                 }
@@ -2,37 +2,42 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
-use std::{collections::BTreeSet, fs::File, path::PathBuf};
+use std::path::PathBuf;
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::Result;
 use clap::Parser;
 use crabbletalk::{
-    aarp::AarpStackHandle,
-    addr::{Appletalk, AppletalkNode, AppletalkSocket, DdpType},
+    aarp::{AarpStackHandle, StackConfig},
+    addr::{Appletalk, AppletalkNode, DdpType, APPLETALK_SERVER_NODE_RANGE},
     ddp::DdpHeader,
+    CrabbletalkError,
 };
 use cruats::{
     at::at_addr,
-    zerocopy::{FromBytes, LayoutVerified, Unalign, Unaligned},
+    zerocopy::{LayoutVerified, Unalign},
 };
 use packed_struct::PrimitiveEnum;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::{unix::SocketAddr, UnixDatagram, UnixStream},
+    net::{UnixDatagram, UnixStream},
 };
+use tracing::Instrument;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Cli {
     #[clap(short, long)]
     tmpdir: Option<PathBuf>,
+    /// where to remember our AppleTalk address between runs
+    #[clap(short, long)]
+    state_file: Option<PathBuf>,
     router_path: PathBuf,
     cruats_path: PathBuf,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    console_subscriber::init();
+    crabbletalk_afpd::init_tracing(true);
     let cli = Cli::parse();
     let (ethertalk, _unlinker1) = crabbletalk_afpd::anonymous_datagram_client(
         "cruatsd",
@@ -47,35 +52,51 @@ async fn main() -> Result<()> {
 
     let mut ethertalk_buf = vec![0u8; 1600];
     let mac = crabbletalk::addr::Mac::new_random();
-    println!("cruatsd starting up on {:?}", mac);
-    let (aarp_stack, mut atalk_rx) = crabbletalk::aarp::AarpStackHandle::spawn(mac);
+    tracing::info!(?mac, "cruatsd starting up");
+    let mut config = StackConfig::new(mac).node_range(APPLETALK_SERVER_NODE_RANGE);
+    if let Some(state_file) = &cli.state_file {
+        let hint = crabbletalk_afpd::load_hint(state_file);
+        tracing::info!(?hint, "last address");
+        config = config.address_hint(hint);
+    }
+    let (aarp_stack, mut atalk_rx) = AarpStackHandle::spawn(config)?;
     let mut joinset = tokio::task::JoinSet::new();
+    joinset.spawn(crabbletalk_afpd::report_stats(aarp_stack.clone()));
+    if let Some(state_file) = cli.state_file {
+        joinset.spawn(crabbletalk_afpd::persist_addresses(
+            aarp_stack.clone(),
+            state_file,
+        ));
+    }
 
     loop {
         tokio::select! {
             _ = tokio::signal::ctrl_c() => { break }
-            joined = joinset.join_one(), if !joinset.is_empty() => {
-                println!("ok well we joined {:?}", joined);
+            joined = joinset.join_next(), if !joinset.is_empty() => {
+                match joined {
+                    Some(Ok(Ok(()))) | None => tracing::debug!("task finished"),
+                    Some(Ok(Err(e))) => tracing::warn!(error = ?e, "task failed"),
+                    Some(Err(e)) => tracing::warn!(error = ?e, "task panicked"),
+                }
             }
             recvd = ethertalk.recv_from(&mut ethertalk_buf) => {
-                let (n_read, addr) = recvd?;
+                let (n_read, _addr) = recvd?;
                 let data = &ethertalk_buf[..n_read];
-                println!("ethertalk in in");
                 aarp_stack.process_ethernet(data).await?;
-                println!("ethertalk in out");
             }
             accepted = cruats_control.accept() => {
                 let (stream, addr) = accepted?;
-                joinset.spawn(drive_stream(aarp_stack.clone(), stream, addr));
+                let span = tracing::info_span!("client", ?addr, cred = ?stream.peer_cred().ok());
+                joinset.spawn(drive_stream(aarp_stack.clone(), stream).instrument(span));
             }
             atalk = atalk_rx.recv() => {
                 match &atalk {
                     Some(p) => {
-                        println!("oh no {:?}", p);
+                        tracing::trace!(?p, "to the router");
                         ethertalk.send(&p.0[..]).await?;
                     }
                     None => {
-                        println!("oh no bye??");
+                        tracing::info!("stack went away");
                         break;
                     }
                 }
@@ -83,26 +104,26 @@ async fn main() -> Result<()> {
         }
     }
 
-    println!("draining joinset of {:?}", joinset.len());
+    tracing::info!(tasks = joinset.len(), "shutting down");
     joinset.abort_all();
-    while !joinset.is_empty() {
-        joinset.join_one().await?;
+    while let Some(joined) = joinset.join_next().await {
+        match joined {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::warn!(error = ?e, "task failed"),
+            Err(e) if e.is_cancelled() => {}
+            Err(e) => tracing::warn!(error = ?e, "task panicked"),
+        }
     }
 
     Ok(())
 }
 
-async fn drive_stream(
-    aarp_stack: AarpStackHandle,
-    mut stream: UnixStream,
-    addr: SocketAddr,
-) -> Result<()> {
+async fn drive_stream(aarp_stack: AarpStackHandle, mut stream: UnixStream) -> Result<()> {
     use std::{mem::size_of, os::unix::io::IntoRawFd};
 
     use cruats::at::sockaddr_at;
     use sendfd::SendWithFd;
-    let cred = stream.peer_cred();
-    println!("well who do we got here {:?} {:?}", addr, cred);
+    tracing::info!("client connected");
     let mut buffer = [0u8; 1600];
     let ddp_socket;
     let sock;
@@ -111,12 +132,12 @@ async fn drive_stream(
     {
         let addr_buf = &mut buffer[..size_of::<sockaddr_at>()];
         let n_read = stream.read_exact(addr_buf).await?;
-        ddp_socket = AppletalkSocket::new_random_dynamic();
-        sock = aarp_stack.open_ddp(ddp_socket).await?;
+        sock = aarp_stack.open_ddp_dynamic().await?;
+        ddp_socket = sock.local_socket();
         let (mine_, theirs) = UnixDatagram::pair()?;
         mine = mine_;
         let theirs = theirs.into_std()?.into_raw_fd();
-        println!("here's {} bytes: {:?}", n_read, addr_buf);
+        tracing::trace!(n_read, ?addr_buf, "client sockaddr");
         {
             let lv = LayoutVerified::<_, Unalign<sockaddr_at>>::new(&mut addr_buf[..])
                 .expect("rust internal error?")
@@ -131,11 +152,11 @@ async fn drive_stream(
                 sat_port: sock.local_socket().to_primitive() as i16,
                 ..Default::default()
             });
-            println!("in: {:#?} out: {:#?}", my_addr, lv.into_inner());
+            tracing::debug!(asked = ?my_addr, given = ?lv.into_inner(), socket = ?ddp_socket, "bound");
         }
         stream.writable().await?;
         let res = stream.send_with_fd(addr_buf, &[theirs]);
-        println!("fd/buf: {:?}", res);
+        tracing::trace!(?res, "passed the socket along");
     }
     stream.shutdown().await?;
     drop(stream);
@@ -152,21 +173,32 @@ async fn drive_stream(
                     )
                 })?;
         let addr_in = addr_in.into_ref().get();
-        println!("from {:?} {:?}: {:?}", cred, addr_in, payload);
-        sock.sendto(
-            &payload[..],
-            DdpHeader {
+        tracing::trace!(?addr_in, len = payload.len(), "from client");
+        let res = sock
+            .sendto(&payload[..], DdpHeader {
                 addr: Appletalk {
                     net: addr_in.sat_addr.s_net,
                     node: AppletalkNode::Node(addr_in.sat_addr.s_node as u8),
                 },
                 socket: ddp_socket,
-                typ: DdpType {
-                    typ: addr_in.sat_type as u8,
-                },
-            },
-        )
-        .await?;
+                // netatalk's sendto takes the DDP type from the first byte
+                typ: DdpType::from(addr_in.sat_type as u8),
+            })
+            .await;
+        match res {
+            Ok(()) => {}
+            Err(
+                e @ (CrabbletalkError::ResolutionTimeout(_)
+                | CrabbletalkError::PendingQueueFull(_)
+                | CrabbletalkError::NotAcquired
+                | CrabbletalkError::NoRoute(_)
+                | CrabbletalkError::BroadcastNotEnabled
+                | CrabbletalkError::PayloadTooLarge(_)),
+            ) => {
+                tracing::info!(error = ?e, "couldn't send");
+            }
+            Err(e) => return Err(e.into()),
+        }
     }
     Ok(())
 }
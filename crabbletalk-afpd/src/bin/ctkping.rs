@@ -2,13 +2,13 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
-use std::{collections::BTreeSet, fs::File, os::unix::prelude::FromRawFd, path::PathBuf};
+use std::{os::unix::prelude::FromRawFd, path::PathBuf};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::Result;
 use clap::Parser;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{unix::SocketAddr, UnixDatagram, UnixStream},
+    io::AsyncWriteExt,
+    net::{UnixDatagram, UnixStream},
 };
 
 #[derive(Parser)]
@@ -19,6 +19,7 @@ struct Cli {
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    crabbletalk_afpd::init_tracing(false);
     let cli = Cli::parse();
     let mut cruats_control = UnixStream::connect(&cli.cruats_path).await?;
     use sendfd::RecvWithFd;
@@ -27,12 +28,12 @@ async fn main() -> Result<()> {
     let mut fds = [-1; 2];
     cruats_control.readable().await?;
     let res = cruats_control.recv_with_fd(&mut buffer, &mut fds)?;
-    println!("so what do we got {:?} {:?} {:?}", res, buffer, fds);
+    tracing::info!(?res, ?buffer, ?fds, "got a socket");
     let ddp = unsafe { std::os::unix::net::UnixDatagram::from_raw_fd(fds[0]) };
     let ddp = UnixDatagram::from_std(ddp)?;
     let res = ddp.recv_from(&mut buffer).await;
-    println!("here we go again {:?} {:?}", res, buffer);
+    tracing::info!(?res, ?buffer, "received");
     let res = ddp.send(b"finally").await;
-    println!("and again {:?}", res);
+    tracing::info!(?res, "sent");
     Ok(())
 }
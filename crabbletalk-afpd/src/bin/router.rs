@@ -4,7 +4,7 @@
 
 use std::{collections::BTreeSet, fs::File, path::PathBuf};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::Result;
 use clap::Parser;
 use nix::sys::stat::Mode;
 use pcap_file::PcapWriter;
@@ -24,14 +24,14 @@ impl Cli {
             None => return Ok(None),
         };
         let f = File::create(p)?;
-        println!("opened {:?}", f);
+        tracing::info!(?p, "writing pcap");
         Ok(Some(PcapWriter::new(f)?))
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    //console_subscriber::init();
+    crabbletalk_afpd::init_tracing(false);
     let cli = Cli::parse();
     let mut pcap_writer = cli.pcap_writer()?;
     let listener = tokio::net::UnixDatagram::bind(&cli.socket_path)?;
@@ -45,7 +45,7 @@ async fn main() -> Result<()> {
             r = listener.recv_from(&mut buf) => { r }
         }?;
         let data = &buf[..n_read];
-        println!("read {} bytes from {:?}", n_read, addr);
+        tracing::trace!(n_read, ?addr, "frame");
         if let Some(writer) = &mut pcap_writer {
             if !data.is_empty() {
                 let now = chrono::offset::Utc::now();
@@ -70,7 +70,7 @@ async fn main() -> Result<()> {
                     match listener.send_to(data, client).await {
                         Ok(_) => {}
                         Err(e) => {
-                            println!("error {:?} on {:?}", e, client);
+                            tracing::info!(?client, error = ?e, "dropping client");
                             to_remove.insert(client.to_owned());
                         }
                     }
@@ -7,9 +7,28 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use crabbletalk::{
+    aarp::AarpStackHandle,
+    addr::{Appletalk, AppletalkNode},
+};
+use packed_struct::PrimitiveEnum;
 use tempfile::TempDir;
 
+/// Send events to stderr, filtered by `RUST_LOG` (`info` if unset), and if
+/// `console` is set, serve tokio-console too.
+pub fn init_tracing(console: bool) {
+    use tracing_subscriber::{prelude::*, EnvFilter};
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_filter(filter);
+    tracing_subscriber::registry()
+        .with(console.then(console_subscriber::spawn))
+        .with(fmt)
+        .init();
+}
+
 pub struct UnlinkOnDrop(PathBuf);
 
 impl UnlinkOnDrop {
@@ -49,3 +68,105 @@ pub fn anonymous_datagram_client(
         .with_context(|| format!("whilst binding to {:?}", client_sock))?;
     Ok((sock, client_dir))
 }
+
+/// Read back an address saved by [`save_address`], in netatalk's `net.node`
+/// notation. A missing file just means there's no address to reuse yet.
+pub fn load_address(path: &Path) -> Result<Option<Appletalk>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("whilst reading {:?}", path)),
+    };
+    let (net, node) = contents
+        .trim()
+        .split_once('.')
+        .ok_or_else(|| anyhow!("no net.node in {:?}", path))?;
+    let net = net
+        .parse()
+        .with_context(|| format!("whilst parsing the net in {:?}", path))?;
+    let node = node
+        .parse()
+        .with_context(|| format!("whilst parsing the node in {:?}", path))?;
+    Ok(Some(Appletalk {
+        net,
+        node: AppletalkNode::Node(node),
+    }))
+}
+
+/// [`load_address`] for a saved address that's only a hint: one that can't
+/// be read back is logged and treated as missing rather than stopping us from
+/// starting.
+pub fn load_hint(path: &Path) -> Option<Appletalk> {
+    match load_address(path) {
+        Ok(hint) => hint,
+        Err(e) => {
+            tracing::warn!(error = ?e, "ignoring the saved address");
+            None
+        }
+    }
+}
+
+pub fn save_address(path: &Path, addr: Appletalk) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let contents = format!("{}.{}\n", addr.net, addr.node.to_primitive());
+    std::fs::write(&tmp, contents).with_context(|| format!("whilst writing {:?}", tmp))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("whilst renaming {:?}", tmp))?;
+    Ok(())
+}
+
+/// Save every address the stack accepts, so a restart can ask for it again.
+/// A failed save is logged and tried again with the next address; this only
+/// returns once the stack has gone away.
+pub async fn persist_addresses(aarp_stack: AarpStackHandle, path: PathBuf) -> Result<()> {
+    let mut addr_rx = aarp_stack.watch_address();
+    loop {
+        let addr = *addr_rx.borrow_and_update();
+        if let Some(addr) = addr {
+            tracing::debug!(?addr, ?path, "saving address");
+            if let Err(e) = save_address(&path, addr) {
+                tracing::warn!(?addr, error = ?e, "couldn't save address");
+            }
+        }
+        addr_rx
+            .changed()
+            .await
+            .context("whilst waiting for an address")?;
+    }
+}
+
+/// Log a snapshot of the stack's counters and sockets each time we get
+/// SIGUSR1.
+pub async fn report_stats(aarp_stack: AarpStackHandle) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut usr1 = signal(SignalKind::user_defined1()).context("whilst handling SIGUSR1")?;
+    while usr1.recv().await.is_some() {
+        let snapshot = aarp_stack
+            .snapshot()
+            .await
+            .context("whilst asking the stack for stats")?;
+        for port in &snapshot.ports {
+            tracing::info!(
+                port = port.port.0,
+                hw = ?port.hw,
+                phase = ?port.phase,
+                network = ?port.network,
+                amt = port.amt_entries,
+                pending = port.pending_resolutions,
+                stats = ?port.stats,
+                "port"
+            );
+        }
+        for socket in &snapshot.sockets {
+            tracing::info!(
+                socket = ?socket.socket,
+                port = socket.port.0,
+                stats = ?socket.stats,
+                "socket"
+            );
+        }
+        tracing::info!(delivery = ?snapshot.delivery, "stack");
+    }
+    Ok(())
+}
@@ -8,6 +8,7 @@ use std::{
         net::{UnixDatagram, UnixStream},
         prelude::FromRawFd,
     },
+    sync::Once,
 };
 
 use anyhow::{Context, Result};
@@ -17,6 +18,22 @@ use libc::{c_int, c_void, size_t, ssize_t};
 use nix::sys::{select::FdSet, socket::MsgFlags};
 use sendfd::RecvWithFd;
 
+/// Logging is off unless `CRUATS_LOG` holds a filter directive, since we're a
+/// guest in someone else's process.
+fn init_logging() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        let filter = match std::env::var("CRUATS_LOG") {
+            Ok(f) => tracing_subscriber::EnvFilter::new(f),
+            Err(_) => return,
+        };
+        let _ = tracing_subscriber::fmt()
+            .with_writer(std::io::stderr)
+            .with_env_filter(filter)
+            .try_init();
+    });
+}
+
 const ADDRLEN: usize = std::mem::size_of::<sockaddr_at>();
 
 fn open_cruats(bind: Option<sockaddr_at>) -> Result<(c_int, sockaddr_at)> {
@@ -55,21 +72,18 @@ macro_rules! errno {
 
 #[no_mangle]
 pub extern "C" fn cruats_ddp_open(addr: *mut sockaddr_at, bridge: *mut sockaddr_at) -> c_int {
-    println!(
-        "*** hello from rust open: addr {:#?} bridge {:#?} ***",
-        unsafe { addr.as_ref() },
-        unsafe { bridge.as_ref() }
-    );
+    init_logging();
+    tracing::debug!(addr = ?unsafe { addr.as_ref() }, bridge = ?unsafe { bridge.as_ref() }, "open");
     let bind = unsafe { addr.as_ref() }.cloned();
     let (fd, addr_out) = match open_cruats(bind) {
         Ok(r) => r,
         Err(e) => {
-            println!("*** rust fucked up: {:#?} ***", e);
+            tracing::warn!(error = ?e, "couldn't open a socket through cruatsd");
             errno!(libc::EACCES);
         }
     };
     if let Some(dest) = unsafe { addr.as_mut() } {
-        println!("*** ok rust open addr out: {:?} ***", addr_out);
+        tracing::debug!(?addr_out, "opened");
         *dest = addr_out;
     }
     fd
@@ -77,7 +91,8 @@ pub extern "C" fn cruats_ddp_open(addr: *mut sockaddr_at, bridge: *mut sockaddr_
 
 #[no_mangle]
 pub extern "C" fn cruats_ddp_close(socket: c_int) -> c_int {
-    println!("*** hello from rust close: {} ***", socket);
+    init_logging();
+    tracing::debug!(socket, "close");
     0
 }
 
@@ -92,14 +107,15 @@ pub extern "C" fn cruats_ddp_sendto(
     addr: *const sockaddr_at,
     addrlen: size_t,
 ) -> ssize_t {
+    init_logging();
     let data = if buf.is_null() || len < 1 {
-        println!("*** bail from rust: data {:p} {} ***", buf, len);
+        tracing::debug!(?buf, len, "sendto: bad buffer");
         errno!(libc::EINVAL);
     } else {
         unsafe { std::slice::from_raw_parts(buf as *const u8, len) }
     };
     let addr = if addr.is_null() || addrlen != ADDRLEN {
-        println!("*** bail from rust: addr {:p} {} ***", addr, addrlen);
+        tracing::debug!(?addr, addrlen, "sendto: bad address");
         errno!(libc::EINVAL);
     } else {
         unsafe { std::slice::from_raw_parts(addr as *const u8, addrlen as usize) }
@@ -113,26 +129,16 @@ pub extern "C" fn cruats_ddp_sendto(
         lv.sat_type = data[0] as i16;
         // netatalk's atalk_aton calls hton, so reverse that
         lv.sat_addr.s_net = u16::from_be(lv.sat_addr.s_net);
-        println!(
-            "*** hello from rust sendto: {} {} {}: {:?} \n {} vs {}: {:?}\n {:#?} ***",
-            socket,
-            len,
-            flags,
-            data,
-            addrlen,
-            std::mem::size_of::<sockaddr_at>(),
-            addr,
-            lv,
-        );
+        tracing::trace!(socket, len, flags, ?data, addr = ?lv, "sendto");
     }
     let iov = [IoSlice::new(&addr_local[..]), IoSlice::new(&data[1..])];
     match nix::sys::socket::sendmsg::<()>(socket, &iov[..], &[], NO_FLAGS, None) {
         Ok(n) => {
-            println!("*** sendmsg back {:?} ***", n);
+            tracing::trace!(n, "sendmsg");
             len as isize
         }
         Err(e) => {
-            println!("*** oh no sendmsg failure: {:?} ***", e);
+            tracing::debug!(error = ?e, "sendmsg failed");
             -1
         }
     }
@@ -147,12 +153,10 @@ pub extern "C" fn cruats_ddp_recvfrom(
     addr: *mut sockaddr_at,
     addrlen: *mut size_t,
 ) -> ssize_t {
-    println!(
-        "*** hello from rust recvfrom: {} {} {} ***",
-        socket, len, flags,
-    );
+    init_logging();
+    tracing::trace!(socket, len, flags, "recvfrom");
     let buf = if buf.is_null() || len < 1 {
-        println!("*** bail from rust: data {:p} {} ***", buf, len);
+        tracing::debug!(?buf, len, "recvfrom: bad buffer");
         errno!(libc::EINVAL);
     } else {
         unsafe { std::slice::from_raw_parts_mut(buf as *mut u8, len) }
@@ -163,26 +167,25 @@ pub extern "C" fn cruats_ddp_recvfrom(
     loop {
         match nix::sys::select::select(readers.highest(), Some(&mut readers), None, None, None) {
             Err(nix::errno::Errno::EINTR) => {
-                println!("*** haha eintr");
                 continue;
             }
             Ok(n) => {
-                println!("*** select back {:?} ***", n);
+                tracing::trace!(n, "select");
                 break;
             }
             Err(e) => {
-                println!("*** oh no select failure: {:?} ***", e);
+                tracing::debug!(error = ?e, "select failed");
                 return -1;
             }
         }
     }
     match nix::sys::socket::recvmsg::<()>(socket, &mut iov[..], None, NO_FLAGS) {
         Ok(n) => {
-            println!("*** recvmsg back {:?} ***", n);
+            tracing::trace!(?n, "recvmsg");
             len as isize
         }
         Err(e) => {
-            println!("*** oh no recvmsg failure: {:?} ***", e);
+            tracing::debug!(error = ?e, "recvmsg failed");
             -1
         }
     }
@@ -2,24 +2,37 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
-use std::{cell::Cell, collections::BTreeMap, error::Error, fmt, sync::Arc};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    ops::RangeInclusive,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use chrono::{DateTime, Utc};
 use packed_struct::prelude::*;
 use pnet_packet::ethernet::EtherTypes;
-use tokio::{
-    sync::{mpsc, oneshot, watch, Notify, OnceCell, RwLock},
-    task,
-};
-use tokio_stream::StreamExt;
+use rand::RngCore;
+use tracing::{debug, info, trace, warn};
 
 use crate::{
     addr::*,
-    ddp::{Ddp, DdpSocket},
-    link::{AppletalkPacket, Elap},
+    buf::{PacketBuf, FRAME_HEADROOM},
+    ddp::{Ddp, DdpOutbound, DropCounters, DDP_HEADER_LEN, DDP_MAX_PAYLOAD},
+    link::{Elap, ELAP_HEADER_LEN},
+    rtmp::RtmpData,
+    stats::{
+        DeliveryStats, PhaseSummary, PortSnapshot, PortStats, SocketSnapshot, SocketStats,
+        StackSnapshot,
+    },
+    zip::{NetInfoReply, ZipFunction},
     Result, UnpackSplit,
 };
 
+mod driver;
+
+pub use driver::{AarpStackHandle, Clock, DdpControl, StackControl, TokioClock};
+
 #[derive(PrimitiveEnum_u16, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum AarpHardware {
     Ethernet = 1,
@@ -64,132 +77,809 @@ pub struct Aarp {
     pub destination_appletalk: Appletalk,
 }
 
+/// How a tentative address is probed for before it is accepted. The defaults
+/// follow Inside AppleTalk: ten probes, a fifth of a second apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProbeSchedule {
+    /// How many probes to send for each tentative address.
+    pub count: usize,
+    /// How long to wait after each probe for a conflicting response.
+    pub interval: Duration,
+    /// How many conflicting addresses to tolerate before giving up.
+    pub max_conflicts: usize,
+}
+
+impl Default for ProbeSchedule {
+    fn default() -> Self {
+        ProbeSchedule {
+            count: 10,
+            interval: Duration::from_millis(200),
+            max_conflicts: 64,
+        }
+    }
+}
+
+/// Probing for an address: `count` probes for each candidate, `interval`
+/// apart, moving on to the next candidate whenever someone objects.
+#[derive(Debug)]
+struct Acquisition {
+    candidates: Candidates,
+    addr: Appletalk,
+    probes: usize,
+    conflicts: usize,
+    next_probe: Instant,
+}
+
 #[derive(Debug)]
 enum AddressPhase {
-    Uninitialized,
-    Tentative {
-        addr: Appletalk,
-        conflict: std::sync::Arc<tokio::sync::Notify>,
-    },
-    Accepted {
-        addr: Appletalk,
-    },
+    Tentative(Acquisition),
+    Accepted { addr: Appletalk },
+    Failed { conflicts: usize },
 }
 
 impl AddressPhase {
-    async fn acquire(phase_tx: mpsc::Sender<AddressPhase>) -> Result<()> {
-        loop {
-            let addr = Appletalk::new_random();
-            let notifier = std::sync::Arc::new(tokio::sync::Notify::new());
-            phase_tx
-                .send(AddressPhase::Tentative {
-                    addr,
-                    conflict: notifier.clone(),
-                })
-                .await
-                .map_err(|_| crate::CrabbletalkError::Hangup)?;
-            tokio::select! {
-                () = tokio::time::sleep(std::time::Duration::from_millis(1500)) => {
-                    // we won!!
-                    println!("haha we won");
-                }
-                () = notifier.notified() => {
-                    // conflict; try again
-                    println!("conflict");
-                   continue;
-                }
-            }
-            phase_tx
-                .send(AddressPhase::Accepted { addr })
-                .await
-                .map_err(|_| crate::CrabbletalkError::Hangup)?;
-            return Ok(());
+    fn summary(&self) -> PhaseSummary {
+        match *self {
+            AddressPhase::Tentative(ref acq) => PhaseSummary::Probing(acq.addr),
+            AddressPhase::Accepted { addr } => PhaseSummary::Accepted(addr),
+            AddressPhase::Failed { conflicts } => PhaseSummary::Failed { conflicts },
         }
     }
 }
 
-#[derive(Default)]
-struct AmtSynchronized {
-    hw_table: BTreeMap<Mac, Arc<AmtEntry>>,
-    atalk_table: BTreeMap<Appletalk, Arc<AmtEntry>>,
+/// Where tentative addresses come from: the hint once, then random picks.
+#[derive(Debug, Clone)]
+struct Candidates {
+    hint: Option<Appletalk>,
+    nets: RangeInclusive<u16>,
+    nodes: RangeInclusive<u8>,
+}
+
+impl Candidates {
+    fn new(config: &StackConfig) -> Self {
+        Self::in_ranges(config.address_hint, config.net_range.clone(), config)
+    }
+
+    fn in_ranges(hint: Option<Appletalk>, nets: RangeInclusive<u16>, config: &StackConfig) -> Self {
+        let nodes = config.node_range.clone();
+        let hint = hint.filter(|h| {
+            h.is_node_address() && nets.contains(&h.net) && nodes.contains(&h.node.to_primitive())
+        });
+        Candidates { hint, nets, nodes }
+    }
+
+    fn next(&mut self, rng: &mut dyn RngCore) -> Appletalk {
+        self.hint.take().unwrap_or_else(|| {
+            Appletalk::new_random_from(rng, self.nets.clone(), self.nodes.clone())
+        })
+    }
+}
+
+/// Bounds on the AARP address mapping table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AmtLimits {
+    /// How long a mapping is trusted without being refreshed by AARP or gleaning.
+    pub max_age: Duration,
+    /// How many mappings to keep before evicting the least recently used one.
+    pub capacity: usize,
+}
+
+impl Default for AmtLimits {
+    fn default() -> Self {
+        AmtLimits {
+            max_age: Duration::from_secs(5 * 60),
+            capacity: 256,
+        }
+    }
+}
+
+/// Everything tunable about an [`AarpStack`]. `StackConfig::new(hw)` (or just
+/// a [`Mac`]) gives today's defaults; the rest are builder-style overrides.
+#[derive(Debug, Clone)]
+pub struct StackConfig {
+    hw: Mac,
+    node_range: RangeInclusive<u8>,
+    net_range: RangeInclusive<u16>,
+    address_hint: Option<Appletalk>,
+    probe_schedule: ProbeSchedule,
+    aarp_request_interval: Duration,
+    aarp_request_attempts: usize,
+    net_info_interval: Duration,
+    net_info_attempts: usize,
+    router_max_age: Duration,
+    glean_ddp: bool,
+    amt_limits: AmtLimits,
+    link_queue_depth: usize,
+    input_queue_depth: usize,
+    control_queue_depth: usize,
+    socket_queue_depth: usize,
+    pending_queue_depth: usize,
+    event_queue_depth: usize,
+}
+
+impl StackConfig {
+    pub fn new(hw: Mac) -> Self {
+        StackConfig {
+            hw,
+            node_range: APPLETALK_ANY_NODE_RANGE,
+            net_range: APPLETALK_STARTUP_NET_RANGE,
+            address_hint: None,
+            probe_schedule: Default::default(),
+            aarp_request_interval: Duration::from_millis(200),
+            aarp_request_attempts: 10,
+            net_info_interval: Duration::from_secs(1),
+            net_info_attempts: 3,
+            router_max_age: Duration::from_secs(50),
+            glean_ddp: false,
+            amt_limits: Default::default(),
+            link_queue_depth: 25,
+            input_queue_depth: 1,
+            control_queue_depth: 1,
+            socket_queue_depth: 25,
+            pending_queue_depth: 8,
+            event_queue_depth: 64,
+        }
+    }
+
+    /// The link address this port uses.
+    pub fn hw(&self) -> Mac {
+        self.hw
+    }
+
+    /// Which node numbers to pick from, e.g. [`APPLETALK_SERVER_NODE_RANGE`]
+    /// for servers.
+    pub fn node_range(mut self, range: RangeInclusive<u8>) -> Self {
+        self.node_range = range;
+        self
+    }
+
+    /// Which nets to pick from before a router tells us otherwise.
+    pub fn net_range(mut self, range: RangeInclusive<u16>) -> Self {
+        self.net_range = range;
+        self
+    }
+
+    /// Try to reacquire a previously-used address first, as Inside AppleTalk
+    /// recommends, so that peers' cached mappings stay valid across restarts.
+    pub fn address_hint(mut self, hint: Option<Appletalk>) -> Self {
+        self.address_hint = hint;
+        self
+    }
+
+    pub fn probe_schedule(mut self, schedule: ProbeSchedule) -> Self {
+        self.probe_schedule = schedule;
+        self
+    }
+
+    /// How often, and how many times, to ask for an unknown hardware address
+    /// before failing the datagrams waiting on it.
+    pub fn aarp_requests(mut self, interval: Duration, attempts: usize) -> Self {
+        self.aarp_request_interval = interval;
+        self.aarp_request_attempts = attempts;
+        self
+    }
+
+    /// How often, and how many times, to ask for the cable's network range
+    /// with ZIP GetNetInfo once a provisional address is acquired. If no
+    /// router answers, the stack stays in the startup range.
+    pub fn get_net_info(mut self, interval: Duration, attempts: usize) -> Self {
+        self.net_info_interval = interval;
+        self.net_info_attempts = attempts;
+        self
+    }
+
+    /// How long to keep sending off-net datagrams to the last router heard
+    /// from, after which they go to the broadcast address until another one
+    /// speaks up.
+    pub fn router_max_age(mut self, age: Duration) -> Self {
+        self.router_max_age = age;
+        self
+    }
+
+    /// Learn AMT entries from the link and DDP source addresses of incoming
+    /// datagrams which haven't crossed a router, saving an AARP round trip
+    /// when replying to them.
+    pub fn glean_ddp(mut self, glean: bool) -> Self {
+        self.glean_ddp = glean;
+        self
+    }
+
+    pub fn amt_limits(mut self, limits: AmtLimits) -> Self {
+        self.amt_limits = limits;
+        self
+    }
+
+    /// Depth of the queue of frames waiting to go out on the link.
+    pub fn link_queue_depth(mut self, depth: usize) -> Self {
+        self.link_queue_depth = depth;
+        self
+    }
+
+    /// Depth of the queue of frames handed to the stack from the link.
+    pub fn input_queue_depth(mut self, depth: usize) -> Self {
+        self.input_queue_depth = depth;
+        self
+    }
+
+    pub fn control_queue_depth(mut self, depth: usize) -> Self {
+        self.control_queue_depth = depth;
+        self
+    }
+
+    /// Depth of each socket's queue of received datagrams.
+    pub fn socket_queue_depth(mut self, depth: usize) -> Self {
+        self.socket_queue_depth = depth;
+        self
+    }
+
+    /// How many datagrams may wait on AARP resolution per destination.
+    pub fn pending_queue_depth(mut self, depth: usize) -> Self {
+        self.pending_queue_depth = depth;
+        self
+    }
+
+    /// How many events a subscriber can fall behind by before it starts
+    /// missing them.
+    pub fn event_queue_depth(mut self, depth: usize) -> Self {
+        self.event_queue_depth = depth;
+        self
+    }
+
+    /// Check for settings the stack can't run with, such as an empty or
+    /// reserved range of addresses to pick from.
+    pub fn validate(&self) -> Result<()> {
+        use crate::CrabbletalkError::InvalidConfig;
+        let nodes = &self.node_range;
+        if nodes.is_empty() {
+            return Err(InvalidConfig("empty node range"));
+        }
+        if !APPLETALK_ANY_NODE_RANGE.contains(nodes.start())
+            || !APPLETALK_ANY_NODE_RANGE.contains(nodes.end())
+        {
+            return Err(InvalidConfig("node range includes reserved nodes"));
+        }
+        let nets = &self.net_range;
+        if nets.is_empty() {
+            return Err(InvalidConfig("empty net range"));
+        }
+        if *nets.start() == 0 || *nets.end() == 0xFFFF {
+            return Err(InvalidConfig("net range includes reserved nets"));
+        }
+        let depths = [
+            self.link_queue_depth,
+            self.input_queue_depth,
+            self.control_queue_depth,
+            self.socket_queue_depth,
+            self.pending_queue_depth,
+            self.event_queue_depth,
+        ];
+        if depths.contains(&0) {
+            return Err(InvalidConfig("zero queue depth"));
+        }
+        Ok(())
+    }
+}
+
+impl From<Mac> for StackConfig {
+    fn from(hw: Mac) -> Self {
+        StackConfig::new(hw)
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
 struct AmtEntryCell {
     hw: Mac,
-    atalk: Appletalk,
-    set_at: DateTime<Utc>,
+    set_at: Instant,
+    used_at: Instant,
 }
 
-struct AmtEntry {
-    entry_tx: watch::Sender<Option<AmtEntryCell>>,
-    entry_rx: watch::Receiver<Option<AmtEntryCell>>,
+/// AARP address mapping table. Both directions are kept as a one-to-one
+/// mapping, so an address moving to a new MAC (or a MAC taking a new address)
+/// drops the stale half.
+#[derive(Debug)]
+struct Amt {
+    limits: AmtLimits,
+    hw_table: BTreeMap<Mac, Appletalk>,
+    atalk_table: BTreeMap<Appletalk, AmtEntryCell>,
+    /// Entries learned and forgotten, for the port to report.
+    changes: VecDeque<StackEventKind>,
 }
 
-impl Default for AmtEntry {
-    fn default() -> Self {
-        let (entry_tx, entry_rx) = watch::channel(None);
-        AmtEntry { entry_tx, entry_rx }
+impl Amt {
+    fn new(limits: AmtLimits) -> Self {
+        Amt {
+            limits,
+            hw_table: Default::default(),
+            atalk_table: Default::default(),
+            changes: Default::default(),
+        }
+    }
+
+    fn insert(&mut self, hw: Mac, atalk: Appletalk, now: Instant) {
+        if let Some(old_atalk) = self.hw_table.insert(hw, atalk) {
+            if old_atalk != atalk {
+                debug!(?hw, from = ?old_atalk, to = ?atalk, "amt: hardware address moved");
+                self.atalk_table.remove(&old_atalk);
+                self.changes.push_back(StackEventKind::AmtForgotten {
+                    atalk: old_atalk,
+                    hw,
+                });
+            }
+        }
+        let learned = self.atalk_table.get(&atalk).map(|old| old.hw) != Some(hw);
+        let used_at = match self.atalk_table.get(&atalk) {
+            Some(old) if old.hw != hw => {
+                debug!(?atalk, from = ?old.hw, to = ?hw, "amt: appletalk address moved");
+                self.hw_table.remove(&old.hw);
+                now
+            }
+            Some(old) => old.used_at,
+            None => now,
+        };
+        self.atalk_table.insert(atalk, AmtEntryCell {
+            hw,
+            set_at: now,
+            used_at,
+        });
+        if learned {
+            self.changes
+                .push_back(StackEventKind::AmtLearned { atalk, hw });
+        }
+        while self.atalk_table.len() > self.limits.capacity {
+            let lru = self
+                .atalk_table
+                .iter()
+                .min_by_key(|(_, cell)| cell.used_at)
+                .map(|(&atalk, _)| atalk);
+            match lru {
+                Some(lru) => self.remove(lru),
+                None => break,
+            }
+        }
+    }
+
+    fn remove(&mut self, atalk: Appletalk) {
+        if let Some(cell) = self.atalk_table.remove(&atalk) {
+            self.hw_table.remove(&cell.hw);
+            self.changes
+                .push_back(StackEventKind::AmtForgotten { atalk, hw: cell.hw });
+        }
+    }
+
+    fn lookup(&mut self, atalk: Appletalk, now: Instant) -> Option<Mac> {
+        let max_age = self.limits.max_age;
+        let cell = self.atalk_table.get_mut(&atalk)?;
+        if now.saturating_duration_since(cell.set_at) < max_age {
+            cell.used_at = now;
+            return Some(cell.hw);
+        }
+        self.remove(atalk);
+        None
+    }
+
+    fn next_expiry(&self) -> Option<Instant> {
+        self.atalk_table
+            .values()
+            .map(|cell| cell.set_at + self.limits.max_age)
+            .min()
     }
+
+    fn expire(&mut self, now: Instant) {
+        let max_age = self.limits.max_age;
+        let stale = self
+            .atalk_table
+            .iter()
+            .filter(|(_, cell)| now.saturating_duration_since(cell.set_at) >= max_age)
+            .map(|(&atalk, _)| atalk)
+            .collect::<Vec<_>>();
+        for atalk in stale {
+            trace!(?atalk, "amt: aged out");
+            self.remove(atalk);
+        }
+    }
+}
+/// What the routers on our cable have told us about it, either in a ZIP
+/// GetNetInfo reply or in RTMP broadcasts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkInfo {
+    pub cable_range: RangeInclusive<u16>,
+    /// Unknown until a ZIP GetNetInfo reply arrives.
+    pub zone: Option<Vec<u8>>,
+    pub zone_multicast: Option<Mac>,
+    /// The last router heard from (A-ROUTER).
+    pub router: Appletalk,
+    pub router_hw: Mac,
 }
 
-pub struct AarpStack {
-    appletalk_tx: mpsc::Sender<AppletalkPacket>,
+/// Something that happened on one of a stack's ports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackEvent {
+    pub port: PortId,
+    pub kind: StackEventKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StackEventKind {
+    AddressAcquired(Appletalk),
+    /// Another node claimed our accepted address; we're probing for another.
+    AddressLost {
+        addr: Appletalk,
+        claimed_by: Mac,
+    },
+    /// A router says our accepted address isn't on this cable; we're probing
+    /// for one in `cable_range`.
+    AddressOutOfRange {
+        addr: Appletalk,
+        cable_range: RangeInclusive<u16>,
+    },
+    /// Someone probed for or answered for the address we were probing.
+    Conflict {
+        addr: Appletalk,
+        from: Mac,
+    },
+    /// Too many conflicts; the port has given up.
+    AcquisitionFailed {
+        conflicts: usize,
+    },
+    NetworkChanged(NetworkInfo),
+    AmtLearned {
+        atalk: Appletalk,
+        hw: Mac,
+    },
+    /// Expired, evicted, or superseded by a different mapping.
+    AmtForgotten {
+        atalk: Appletalk,
+        hw: Mac,
+    },
+}
+
+/// Names a datagram given to [`AarpStack::send_ddp`], so its outcome can be
+/// matched up when it comes out of [`AarpStack::poll_output`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SendId(u64);
+
+/// Something the stack wants done.
+#[derive(Debug)]
+pub enum Output {
+    /// Put a frame on `port`'s link.
+    Transmit {
+        port: PortId,
+        frame: PacketBuf,
+    },
+    /// Hand a datagram to the socket bound on `port`.
+    Deliver {
+        port: PortId,
+        socket: AppletalkSocket,
+        header: Ddp,
+        payload: PacketBuf,
+    },
+    /// How a datagram given to `send_ddp` turned out.
+    Sent {
+        id: SendId,
+        result: Result<()>,
+    },
+    Event(StackEvent),
+}
+
+#[derive(Debug)]
+struct NetInfoQuery {
+    attempts: usize,
+    next_attempt: Instant,
+}
+
+/// Datagrams waiting on an AARP response before they can be addressed.
+#[derive(Debug)]
+struct PendingResolution {
+    queue: VecDeque<(SendId, DdpOutbound)>,
+    attempts: usize,
+    next_attempt: Instant,
+}
+
+/// Which of a stack's links something happened on or is bound to, in the
+/// order their configs were given to the stack.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PortId(pub usize);
+
+/// One link: its MAC, AARP state and address.
+struct Port {
+    id: PortId,
     my_addr_ethernet: Mac,
-    my_addr_appletalk_tx: watch::Sender<Option<Appletalk>>,
-    my_addr_appletalk_rx: watch::Receiver<Option<Appletalk>>,
     phase: AddressPhase,
-    amt: RwLock<AmtSynchronized>,
+    amt: Amt,
+    pending: BTreeMap<Appletalk, PendingResolution>,
+    config: StackConfig,
+    network: Option<NetworkInfo>,
+    net_info_query: Option<NetInfoQuery>,
+    router_heard_at: Option<Instant>,
+    drops: Arc<DropCounters>,
+    stats: PortStats,
+    outbox: VecDeque<Output>,
 }
 
-impl fmt::Debug for AarpStack {
+impl fmt::Debug for Port {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("AarpStack")
+        f.debug_struct("Port")
             .field("my_addr_ethernet", &self.my_addr_ethernet)
             .field("phase", &self.phase)
-            .field("amt_hw2atalk", &"<opaque>")
-            .field("amt_atalk2hw", &"<opaque>")
+            .field("amt", &self.amt)
+            .field("pending", &self.pending.keys().collect::<Vec<_>>())
             .finish()
     }
 }
 
-impl AarpStack {
-    pub fn new(hw: Mac) -> (Self, mpsc::Receiver<AppletalkPacket>) {
-        let (appletalk_tx, appletalk_rx) = mpsc::channel(25);
-        let (my_addr_appletalk_tx, my_addr_appletalk_rx) = watch::channel(None);
-        let ret = AarpStack {
-            appletalk_tx,
-            my_addr_ethernet: hw,
-            my_addr_appletalk_tx,
-            my_addr_appletalk_rx,
-            phase: AddressPhase::Uninitialized,
-            amt: Default::default(),
+/// Bound sockets, each on one port's address, so a multihomed node can bind
+/// the same socket on every port. Which `DdpSocket` each one belongs to is
+/// the driver's business.
+#[derive(Default)]
+struct SocketTable {
+    bound: BTreeMap<(PortId, AppletalkSocket), SocketStats>,
+}
+
+impl SocketTable {
+    fn get_mut(&mut self, port: PortId, socket: AppletalkSocket) -> Option<&mut SocketStats> {
+        self.bound.get_mut(&(port, socket))
+    }
+
+    fn remove(&mut self, port: PortId, socket: AppletalkSocket) {
+        self.bound.remove(&(port, socket));
+    }
+
+    fn is_free(&self, port: PortId, socket: AppletalkSocket) -> bool {
+        !self.bound.contains_key(&(port, socket))
+    }
+
+    /// Pick a dynamic socket unused on `port`, starting the search somewhere
+    /// random.
+    fn allocate_dynamic(&self, port: PortId, rng: &mut dyn RngCore) -> Result<AppletalkSocket> {
+        let start = match AppletalkSocket::new_random_dynamic_from(rng) {
+            AppletalkSocket::Dynamic(n) => n,
+            _ => *APPLETALK_DDP_DAS_RANGE.start(),
+        };
+        let (low, high) = APPLETALK_DDP_DAS_RANGE.into_inner();
+        (start..=high)
+            .chain(low..start)
+            .map(AppletalkSocket::Dynamic)
+            .find(|&s| self.is_free(port, s))
+            .ok_or(crate::CrabbletalkError::SocketsExhausted)
+    }
+
+    fn insert(&mut self, port: PortId, socket: AppletalkSocket) -> Result<()> {
+        if !self.is_free(port, socket) {
+            return Err(crate::CrabbletalkError::SocketInUse(socket));
+        }
+        self.bound.insert((port, socket), Default::default());
+        Ok(())
+    }
+
+    fn sockets(&self) -> Vec<(PortId, AppletalkSocket)> {
+        self.bound.keys().copied().collect()
+    }
+
+    fn snapshot(&self) -> Vec<SocketSnapshot> {
+        self.bound
+            .iter()
+            .map(|(&(port, socket), &stats)| SocketSnapshot {
+                socket,
+                port,
+                stats,
+            })
+            .collect()
+    }
+}
+
+/// The AARP and DDP protocol logic for every port, as a state machine with
+/// no I/O or clock of its own: feed it frames, datagrams to send and the
+/// time, and collect what it wants done from [`AarpStack::poll_output`].
+/// [`AarpStackHandle`] drives one on tokio.
+pub struct AarpStack {
+    ports: Vec<Port>,
+    sockets: SocketTable,
+    delivery: DeliveryStats,
+    outbox: VecDeque<Output>,
+    next_send: u64,
+    rng: Box<dyn RngCore + Send>,
+}
+
+impl fmt::Debug for AarpStack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AarpStack")
+            .field("ports", &self.ports)
+            .field("sockets", &self.sockets.sockets())
+            .finish()
+    }
+}
+
+impl Port {
+    /// A port which starts probing the first time its timer fires.
+    fn new(id: PortId, config: StackConfig, now: Instant, rng: &mut dyn RngCore) -> Self {
+        let mut candidates = Candidates::new(&config);
+        let addr = candidates.next(rng);
+        Port {
+            id,
+            my_addr_ethernet: config.hw,
+            phase: AddressPhase::Tentative(Acquisition {
+                candidates,
+                addr,
+                probes: 0,
+                conflicts: 0,
+                next_probe: now,
+            }),
+            amt: Amt::new(config.amt_limits),
+            pending: Default::default(),
+            config,
+            network: None,
+            net_info_query: None,
+            router_heard_at: None,
+            drops: Default::default(),
+            stats: Default::default(),
+            outbox: Default::default(),
+        }
+    }
+
+    fn event(&mut self, kind: StackEventKind) {
+        self.outbox.push_back(Output::Event(StackEvent {
+            port: self.id,
+            kind,
+        }));
+    }
+
+    fn sent(&mut self, id: SendId, result: Result<()>) {
+        self.outbox.push_back(Output::Sent { id, result });
+    }
+
+    /// Report whatever the AMT just learned or forgot, behind everything
+    /// that came before it.
+    fn amt_changed(&mut self) {
+        while let Some(kind) = self.amt.changes.pop_front() {
+            self.event(kind);
+        }
+    }
+
+    fn poll_output(&mut self) -> Option<Output> {
+        self.outbox.pop_front()
+    }
+
+    /// Start probing for an address from `candidates`, abandoning any
+    /// acquisition already under way.
+    fn start_acquire(&mut self, mut candidates: Candidates, now: Instant, rng: &mut dyn RngCore) {
+        let addr = candidates.next(rng);
+        self.phase = AddressPhase::Tentative(Acquisition {
+            candidates,
+            addr,
+            probes: 0,
+            conflicts: 0,
+            next_probe: now,
+        });
+        self.acquire_step(now);
+    }
+
+    /// Send the next probe if it's due, or accept the address once every
+    /// probe has gone unanswered.
+    fn acquire_step(&mut self, now: Instant) {
+        let schedule = self.config.probe_schedule;
+        let acq = match &mut self.phase {
+            AddressPhase::Tentative(acq) if acq.next_probe <= now => acq,
+            _ => return,
         };
-        (ret, appletalk_rx)
+        let addr = acq.addr;
+        if acq.probes >= schedule.count {
+            info!(?addr, "acquired address");
+            self.phase = AddressPhase::Accepted { addr };
+            self.event(StackEventKind::AddressAcquired(addr));
+            // start asking for the zone, or start over if a query was held
+            // up by reacquiring
+            let zone_known = matches!(&self.network, Some(NetworkInfo { zone: Some(_), .. }));
+            if !zone_known && (self.network.is_none() || self.net_info_query.is_some()) {
+                self.net_info_query = Some(NetInfoQuery {
+                    attempts: 0,
+                    next_attempt: now,
+                });
+                self.retry_net_info(now);
+            }
+            return;
+        }
+        acq.probes += 1;
+        acq.next_probe = now + schedule.interval;
+        if let Err(e) = self.write_probe(addr) {
+            warn!(?addr, error = ?e, "probe failed");
+        }
+    }
+
+    /// Someone objected to the address we're probing for: move on to
+    /// another, or give up if there have been too many.
+    fn conflict(&mut self, from: Mac, now: Instant, rng: &mut dyn RngCore) {
+        let max_conflicts = self.config.probe_schedule.max_conflicts;
+        let acq = match &mut self.phase {
+            AddressPhase::Tentative(acq) => acq,
+            _ => return,
+        };
+        let addr = acq.addr;
+        acq.conflicts += 1;
+        let conflicts = acq.conflicts;
+        debug!(conflicts, ?addr, ?from, "address conflict");
+        if conflicts >= max_conflicts {
+            self.phase = AddressPhase::Failed { conflicts };
+        } else {
+            acq.addr = acq.candidates.next(rng);
+            acq.probes = 0;
+            acq.next_probe = now;
+        }
+        self.event(StackEventKind::Conflict { addr, from });
+        if conflicts >= max_conflicts {
+            self.event(StackEventKind::AcquisitionFailed { conflicts });
+        }
+        self.acquire_step(now);
+    }
+
+    fn address(&self) -> Option<Appletalk> {
+        match self.phase {
+            AddressPhase::Accepted { addr } => Some(addr),
+            _ => None,
+        }
+    }
+
+    /// Whether `net` is this port's network, as far as we know.
+    fn is_local_net(&self, net: u16) -> bool {
+        if let Some(info) = &self.network {
+            return info.cable_range.contains(&net);
+        }
+        // without a router, everyone is somewhere in the range we probed in
+        self.config.net_range.contains(&net) || self.address().map(|a| a.net) == Some(net)
+    }
+
+    /// Where to send datagrams for other networks: A-ROUTER, if we've heard
+    /// from it recently enough.
+    fn router_hw(&self, now: Instant) -> Option<Mac> {
+        let heard_at = self.router_heard_at?;
+        if now.saturating_duration_since(heard_at) > self.config.router_max_age {
+            return None;
+        }
+        self.network.as_ref().map(|info| info.router_hw)
+    }
+
+    fn snapshot(&self) -> PortSnapshot {
+        PortSnapshot {
+            port: self.id,
+            hw: self.my_addr_ethernet,
+            phase: self.phase.summary(),
+            network: self.network.clone(),
+            amt_entries: self.amt.atalk_table.len(),
+            pending_resolutions: self.pending.len(),
+            stats: PortStats {
+                ddp_dropped: crate::ddp::DropReason::ALL
+                    .iter()
+                    .map(|&r| (r, self.drops.get(r)))
+                    .collect(),
+                ..self.stats.clone()
+            },
+        }
     }
 
-    pub async fn process_aarp(&mut self, data: &[u8]) -> Result<()> {
+    fn process_aarp(&mut self, data: &[u8], now: Instant, rng: &mut dyn RngCore) -> Result<()> {
         let (aarp, remainder) = Aarp::unpack_split(data)?;
-        println!("  aarp: {:#?} trailer {:?}", aarp, remainder);
+        trace!(?aarp, trailer = remainder.len(), "aarp");
+        self.stats.aarp_in.count(aarp.function);
+        if aarp.source_hw == self.my_addr_ethernet {
+            // a link that hands our own frames back to us
+            return Ok(());
+        }
+        let tentative = match &self.phase {
+            AddressPhase::Tentative(acq) => Some(acq.addr),
+            _ => None,
+        };
+        let accepted = self.address();
         use self::AarpFunction::*;
-        match (aarp.function, &self.phase) {
-            (Probe, AddressPhase::Tentative { addr, conflict })
-                if addr == &aarp.destination_appletalk =>
-            {
-                println!("tentative conflict 1");
-                conflict.notify_waiters();
+        match aarp.function {
+            Probe if tentative == Some(aarp.destination_appletalk) => {
+                debug!(addr = ?tentative, from = ?aarp.source_hw, "someone else is probing for our address");
+                self.conflict(aarp.source_hw, now, rng);
             }
-            (Response, AddressPhase::Tentative { addr, conflict })
-                if addr == &aarp.source_appletalk =>
-            {
-                println!("tentative conflict 2");
-                conflict.notify_waiters();
+            Response if tentative == Some(aarp.source_appletalk) => {
+                debug!(addr = ?tentative, from = ?aarp.source_hw, "someone else already has our address");
+                self.conflict(aarp.source_hw, now, rng);
             }
-            (Request | Probe, AddressPhase::Accepted { addr })
-                if addr == &aarp.destination_appletalk =>
-            {
-                // TODO: send AARP reply
-                println!("accepted reply");
+            Request | Probe if accepted == Some(aarp.destination_appletalk) => {
+                trace!(to = ?aarp.source_hw, "answering for our address");
                 self.write_aarp((
                     Elap {
                         destination: aarp.source_hw,
@@ -211,401 +901,804 @@ impl AarpStack {
                         function: AarpFunction::Response,
                         source_hw: self.my_addr_ethernet,
                         _pad1: Default::default(),
-                        source_appletalk: *addr,
+                        source_appletalk: aarp.destination_appletalk,
                         destination_hw: aarp.source_hw,
                         _pad2: Default::default(),
                         destination_appletalk: aarp.source_appletalk,
                     },
-                ))
-                .await?;
+                ))?;
+            }
+            Request | Response if accepted == Some(aarp.source_appletalk) => {
+                self.lose_address(aarp.source_hw, now, rng);
             }
             _ => {}
         }
         match aarp.function {
             Request | Response => {
-                println!("aarp glean");
-                self.add_addresses(aarp.source_hw, aarp.source_appletalk)
-                    .await;
+                trace!(hw = ?aarp.source_hw, atalk = ?aarp.source_appletalk, "aarp glean");
+                self.add_addresses(aarp.source_hw, aarp.source_appletalk, now);
+                self.flush_pending(aarp.source_appletalk, aarp.source_hw);
             }
             Probe => {}
         }
         Ok(())
     }
 
-    pub async fn process_ddp(&mut self, elap: &Elap, data: &[u8]) -> Result<()> {
-        let (ddp, payload) = crate::ddp::Ddp::unpack_split(data)?;
-        match self.phase {
-            AddressPhase::Accepted { addr, .. } => {
-                let src = ddp.source();
-                let dest = ddp.destination();
-                if dest == addr {
-                    println!("oh it's to me");
-                } else if dest == APPLETALK_BROADCAST {
-                } else {
-                }
+    /// Handle a DDP datagram that arrived on this port, returning it if it
+    /// should go on to a socket.
+    fn process_ddp(
+        &mut self,
+        elap: &Elap,
+        data: PacketBuf,
+        now: Instant,
+        rng: &mut dyn RngCore,
+    ) -> Result<Option<(Ddp, PacketBuf)>> {
+        let (ddp, rest) = crate::ddp::Ddp::unpack_split(&data)?;
+        trace!(
+            src = ?ddp.source(),
+            src_socket = ?ddp.src_socket,
+            dest = ?ddp.destination(),
+            dest_socket = ?ddp.dest_socket,
+            typ = ?ddp.typ,
+            length = ddp.length,
+            received = rest.len(),
+            "ddp"
+        );
+        let payload = match crate::ddp::validate(data[0], &ddp, rest) {
+            Ok(p) => data.slice(DDP_HEADER_LEN..DDP_HEADER_LEN + p.len()),
+            Err(reason) => {
+                self.drops.count(reason);
+                return Err(crate::MalformedReason::Ddp(reason).into());
+            }
+        };
+        if ddp.hop_count == 0
+            && elap.source != self.my_addr_ethernet
+            && self.address() == Some(ddp.source())
+        {
+            self.lose_address(elap.source, now, rng);
+        }
+        if self.config.glean_ddp {
+            self.glean(elap, &ddp, now);
+        }
+        let addr = match self.address() {
+            Some(addr) => addr,
+            None => return Ok(None),
+        };
+        let dest = ddp.destination();
+        let for_us = dest == addr
+            || (dest.node == AppletalkNode::Broadcast && (dest.net == 0 || dest.net == addr.net));
+        if !for_us {
+            return Ok(None);
+        }
+        self.stats.ddp_in += 1;
+        match (ddp.typ, ddp.dest_socket) {
+            (DdpType::RTMP_DATA, AppletalkSocket::RTMP) => {
+                self.process_rtmp(elap, &ddp, &payload, now, rng)
+            }
+            (DdpType::ZIP, AppletalkSocket::ZIP) => {
+                self.process_zip(elap, &ddp, &payload, now, rng)
             }
             _ => {}
         }
-        println!(
-            "- ddp: {:?}#{:?} -> {:?}#{:?}; {}b (vs {}b)",
-            ddp.source(),
-            ddp.src_socket,
-            ddp.destination(),
-            ddp.dest_socket,
-            ddp.length,
-            payload.len()
-        );
-        Ok(())
+        Ok(Some((ddp, payload)))
     }
 
-    async fn add_addresses(&self, hw: Mac, atalk: Appletalk) {
-        let mut amt = self.amt.write().await;
-        let entry = amt.atalk_table.entry(atalk).or_default().clone();
-        amt.hw_table.entry(hw).or_insert_with(|| entry.clone());
-        let new = Some(AmtEntryCell {
-            hw,
-            atalk,
-            set_at: Utc::now(),
+    fn process_rtmp(
+        &mut self,
+        elap: &Elap,
+        ddp: &Ddp,
+        payload: &[u8],
+        now: Instant,
+        rng: &mut dyn RngCore,
+    ) {
+        let rtmp = match RtmpData::parse(payload) {
+            Ok(r) => r,
+            Err(e) => {
+                debug!(src = ?ddp.source(), error = ?e, "bad rtmp");
+                return;
+            }
+        };
+        if ddp.hop_count != 0 || !rtmp.router.is_node_address() {
+            return;
+        }
+        trace!(?rtmp, "rtmp");
+        self.add_addresses(elap.source, rtmp.router, now);
+        let info = match &self.network {
+            Some(info) => NetworkInfo {
+                router: rtmp.router,
+                router_hw: elap.source,
+                ..info.clone()
+            },
+            None => NetworkInfo {
+                cable_range: rtmp.cable_range,
+                zone: None,
+                zone_multicast: None,
+                router: rtmp.router,
+                router_hw: elap.source,
+            },
+        };
+        self.learn_network(info, now, rng);
+    }
+
+    fn process_zip(
+        &mut self,
+        elap: &Elap,
+        ddp: &Ddp,
+        payload: &[u8],
+        now: Instant,
+        rng: &mut dyn RngCore,
+    ) {
+        if payload.first() != Some(&(ZipFunction::GetNetInfoReply as u8)) {
+            return;
+        }
+        let reply = match NetInfoReply::parse(payload) {
+            Ok(r) => r,
+            Err(e) => {
+                debug!(src = ?ddp.source(), error = ?e, "bad zip");
+                return;
+            }
+        };
+        debug!(?reply, "zip GetNetInfo reply");
+        self.net_info_query = None;
+        let info = NetworkInfo {
+            cable_range: reply.cable_range(),
+            zone: Some(reply.effective_zone().to_owned()),
+            zone_multicast: reply.multicast,
+            router: ddp.source(),
+            router_hw: elap.source,
+        };
+        self.learn_network(info, now, rng);
+    }
+
+    /// Record what a router told us, and if our provisional address isn't
+    /// inside the cable's range, go back to probing for one that is.
+    fn learn_network(&mut self, info: NetworkInfo, now: Instant, rng: &mut dyn RngCore) {
+        let range = info.cable_range.clone();
+        if range.is_empty() || range.contains(&0) || range.contains(&0xFFFF) {
+            warn!(?range, "ignoring bogus cable range");
+            return;
+        }
+        self.router_heard_at = Some(now);
+        if self.network.as_ref() != Some(&info) {
+            self.network = Some(info.clone());
+            self.event(StackEventKind::NetworkChanged(info));
+        }
+        let (addr, reacquiring) = match &self.phase {
+            AddressPhase::Tentative(acq) => (acq.addr, Some(acq.candidates.nets.clone())),
+            AddressPhase::Accepted { addr } => (*addr, None),
+            AddressPhase::Failed { .. } => return,
+        };
+        if !range.contains(&addr.net) && reacquiring.as_ref() != Some(&range) {
+            info!(
+                ?addr,
+                ?range,
+                "address isn't in the cable range; reacquiring"
+            );
+            if reacquiring.is_none() {
+                self.event(StackEventKind::AddressOutOfRange {
+                    addr,
+                    cable_range: range.clone(),
+                });
+            }
+            let candidates = Candidates::in_ranges(self.config.address_hint, range, &self.config);
+            self.start_acquire(candidates, now, rng);
+        }
+    }
+
+    /// Someone else is using our accepted address. Stop answering for it,
+    /// tell sockets it's gone, and probe for a fresh one.
+    fn lose_address(&mut self, other: Mac, now: Instant, rng: &mut dyn RngCore) {
+        let addr = match self.address() {
+            Some(addr) => addr,
+            None => return,
+        };
+        warn!(
+            ?other,
+            ?addr,
+            "another node claims our address; reacquiring"
+        );
+        self.amt.remove(addr);
+        self.amt_changed();
+        self.event(StackEventKind::AddressLost {
+            addr,
+            claimed_by: other,
         });
-        println!("new value for {:?}/{:?}: {:?}", hw, atalk, new);
-        let old = entry.entry_tx.send_replace(new);
-        println!("old value for {:?}/{:?}: {:?}", hw, atalk, old);
+        let nets = match &self.network {
+            Some(info) => info.cable_range.clone(),
+            None => self.config.net_range.clone(),
+        };
+        self.start_acquire(Candidates::in_ranges(None, nets, &self.config), now, rng);
+    }
+
+    fn send_get_net_info(&mut self) -> Result<()> {
+        let payload =
+            PacketBuf::with_headroom(FRAME_HEADROOM, &crate::zip::pack_get_net_info(&[])?);
+        let ddp = Ddp {
+            _reserved: Default::default(),
+            hop_count: 0,
+            length: 0,
+            checksum: 0,
+            dest_net: 0,
+            src_net: 0,
+            dest_node: AppletalkNode::Broadcast,
+            src_node: AppletalkNode::Unknown,
+            dest_socket: AppletalkSocket::ZIP,
+            src_socket: AppletalkSocket::ZIP,
+            typ: DdpType::ZIP,
+        };
+        self.write_ddp_to(APPLETALK_BROADCAST_MAC, ddp, payload)
+    }
+
+    fn add_addresses(&mut self, hw: Mac, atalk: Appletalk, now: Instant) {
+        trace!(?hw, ?atalk, "amt: learned");
+        self.amt.insert(hw, atalk, now);
+        self.amt_changed();
+    }
+
+    fn hw_from_appletalk(&mut self, atalk: Appletalk, now: Instant) -> Option<Mac> {
+        let potential = self.amt.lookup(atalk, now);
+        self.amt_changed();
+        trace!(?atalk, found = ?potential, "amt: lookup");
+        potential
     }
 
-    async fn hw_from_appletalk(&self, atalk: Appletalk) -> Result<Mac> {
-        let mut rx = {
-            let entry = {
-                let mut amt = self.amt.write().await;
-                println!("hw4a keys {:?}", amt.atalk_table.keys().collect::<Vec<_>>());
-                amt.atalk_table.entry(atalk).or_default().clone()
+    fn glean(&mut self, elap: &Elap, ddp: &Ddp, now: Instant) {
+        let src = ddp.source();
+        if ddp.hop_count != 0 || elap.source == self.my_addr_ethernet {
+            return;
+        }
+        if !matches!(src.node, AppletalkNode::Node(_)) || src.net == 0 {
+            return;
+        }
+        trace!(hw = ?elap.source, atalk = ?src, "ddp glean");
+        self.add_addresses(elap.source, src, now);
+        self.flush_pending(src, elap.source);
+    }
+
+    /// Whether we're in `zone`. Until a router says otherwise, we're in
+    /// whatever zone is asked about.
+    fn in_zone(&self, zone: &[u8]) -> bool {
+        match &self.network {
+            Some(NetworkInfo {
+                zone: Some(ours), ..
+            }) => ours.eq_ignore_ascii_case(zone),
+            _ => true,
+        }
+    }
+
+    /// The multicast address for `zone`, preferring the one a router gave us
+    /// for our own zone.
+    fn zone_multicast(&self, zone: &[u8]) -> Mac {
+        if let Some(info) = &self.network {
+            let ours = info.zone.as_deref().map(|z| z.eq_ignore_ascii_case(zone));
+            if let (Some(true), Some(hw)) = (ours, info.zone_multicast) {
+                return hw;
+            }
+        }
+        crate::zip::zone_multicast(zone)
+    }
+
+    fn send_ddp(&mut self, id: SendId, out: DdpOutbound, now: Instant) {
+        let dest = out.header.destination();
+        if self.address().is_none() {
+            self.sent(id, Err(crate::CrabbletalkError::NotAcquired));
+            return;
+        }
+        let local = dest.net == 0 || self.is_local_net(dest.net);
+        if dest.node == AppletalkNode::Broadcast && !out.broadcast {
+            self.sent(id, Err(crate::CrabbletalkError::BroadcastNotEnabled));
+            return;
+        }
+        if dest.node == AppletalkNode::Broadcast && local {
+            let hw = match &out.zone {
+                Some(zone) => self.zone_multicast(zone),
+                None => APPLETALK_BROADCAST_MAC,
             };
-            let potential = entry.entry_rx.borrow();
-            println!("hw4a for {:?} borrowed {:?}", atalk, potential);
-            if let &Some(AmtEntryCell { hw, .. }) = &*potential {
-                return Ok(hw);
+            let res = self.write_ddp_to(hw, out.header, out.payload);
+            self.sent(id, res);
+            return;
+        }
+        // a directed broadcast to another network goes via the router, which
+        // broadcasts it on arrival
+        if !local {
+            // routers never forward to startup or reserved networks
+            if APPLETALK_STARTUP_NET_RANGE.contains(&dest.net) || dest.net == 0xFFFF {
+                self.sent(id, Err(crate::CrabbletalkError::NoRoute(dest)));
+                return;
             }
-            entry.entry_rx.clone()
+            let hw = self.router_hw(now);
+            trace!(?dest, router = ?hw, "off-net");
+            let hw = hw.unwrap_or(APPLETALK_BROADCAST_MAC);
+            let res = self.write_ddp_to(hw, out.header, out.payload);
+            self.sent(id, res);
+            return;
+        }
+        if let Some(hw) = self.hw_from_appletalk(dest, now) {
+            let res = self.write_ddp_to(hw, out.header, out.payload);
+            self.sent(id, res);
+            return;
+        }
+        let pending = self
+            .pending
+            .entry(dest)
+            .or_insert_with(|| PendingResolution {
+                queue: VecDeque::new(),
+                attempts: 0,
+                next_attempt: now,
+            });
+        if pending.queue.len() >= self.config.pending_queue_depth {
+            self.sent(id, Err(crate::CrabbletalkError::PendingQueueFull(dest)));
+            return;
+        }
+        pending.queue.push_back((id, out));
+        if pending.attempts == 0 {
+            self.retry_pending(now);
+        }
+    }
+
+    fn flush_pending(&mut self, atalk: Appletalk, hw: Mac) {
+        let pending = match self.pending.remove(&atalk) {
+            Some(p) => p,
+            None => return,
         };
-        let mut addr_stream =
-            tokio_stream::wrappers::WatchStream::new(self.my_addr_appletalk_rx.clone());
-        let mut drive_aarp = true;
-        let mut aarp_fut = async move {
-            let addr = loop {
-                println!("spin, spin,");
-                match addr_stream.next().await {
-                    Some(Some(x)) => break x,
-                    Some(None) => continue,
-                    None => return Err(crate::CrabbletalkError::Hangup),
-                }
+        trace!(
+            count = pending.queue.len(),
+            ?atalk,
+            "flushing datagrams waiting on aarp"
+        );
+        for (id, out) in pending.queue {
+            let res = self.write_ddp_to(hw, out.header, out.payload);
+            self.sent(id, res);
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        let pending = self.pending.values().map(|p| p.next_attempt);
+        let net_info = (self.net_info_query.as_ref())
+            .filter(|_| self.address().is_some())
+            .map(|q| q.next_attempt);
+        let probe = match &self.phase {
+            AddressPhase::Tentative(acq) => Some(acq.next_probe),
+            _ => None,
+        };
+        pending
+            .chain(self.amt.next_expiry())
+            .chain(net_info)
+            .chain(probe)
+            .min()
+    }
+
+    fn on_timer(&mut self, now: Instant) {
+        self.amt.expire(now);
+        self.amt_changed();
+        self.retry_pending(now);
+        self.retry_net_info(now);
+        self.acquire_step(now);
+    }
+
+    fn retry_net_info(&mut self, now: Instant) {
+        if self.address().is_none() {
+            // held until we've an address to ask from
+            return;
+        }
+        let query = match &mut self.net_info_query {
+            Some(q) if q.next_attempt <= now => q,
+            _ => return,
+        };
+        if query.attempts >= self.config.net_info_attempts {
+            info!("no ZIP answer; staying in the startup range");
+            self.net_info_query = None;
+            return;
+        }
+        query.attempts += 1;
+        query.next_attempt = now + self.config.net_info_interval;
+        match self.send_get_net_info() {
+            Ok(()) => {}
+            Err(e) => warn!(error = ?e, "GetNetInfo failed"),
+        }
+    }
+
+    fn retry_pending(&mut self, now: Instant) {
+        let due = self
+            .pending
+            .iter()
+            .filter(|(_, p)| p.next_attempt <= now)
+            .map(|(&atalk, _)| atalk)
+            .collect::<Vec<_>>();
+        for atalk in due {
+            let pending = match self.pending.get_mut(&atalk) {
+                Some(p) => p,
+                None => continue,
             };
-            self.write_aarp((
+            if pending.attempts >= self.config.aarp_request_attempts {
+                debug!(?atalk, "giving up on aarp resolution");
+                if let Some(pending) = self.pending.remove(&atalk) {
+                    for (id, _) in pending.queue {
+                        self.sent(id, Err(crate::CrabbletalkError::ResolutionTimeout(atalk)));
+                    }
+                }
+                continue;
+            }
+            pending.attempts += 1;
+            pending.next_attempt = now + self.config.aarp_request_interval;
+            match self.write_aarp_request(atalk) {
+                Ok(()) => {}
+                Err(e) => warn!(?atalk, error = ?e, "aarp request failed"),
+            }
+        }
+    }
+
+    fn write_aarp_request(&mut self, atalk: Appletalk) -> Result<()> {
+        let addr = match self.address() {
+            Some(addr) => addr,
+            // nothing to ask with yet; the retry will come back around
+            None => return Ok(()),
+        };
+        self.write_aarp((
+            Elap {
+                destination: APPLETALK_BROADCAST_MAC,
+                source: self.my_addr_ethernet,
+                length: 0,
+                dsap: SNAP,
+                ig: false,
+                ssap: SNAP,
+                cr: false,
+                control: 3,
+                oui: ZERO_OUI,
+                ethertype: EtherTypes::Aarp.into(),
+            },
+            Aarp {
+                hardware: AarpHardware::Ethernet,
+                protocol: EtherTypes::AppleTalk.into(),
+                hw_address_len: 6,
+                protocol_address_len: 4,
+                function: AarpFunction::Request,
+                source_hw: self.my_addr_ethernet,
+                _pad1: Default::default(),
+                source_appletalk: addr,
+                destination_hw: ZERO_MAC,
+                _pad2: Default::default(),
+                destination_appletalk: atalk,
+            },
+        ))
+    }
+
+    fn write_probe(&mut self, addr: Appletalk) -> Result<()> {
+        self.write_aarp((
+            Elap {
+                destination: APPLETALK_BROADCAST_MAC,
+                source: self.my_addr_ethernet,
+                length: 0,
+                dsap: SNAP,
+                ig: false,
+                ssap: SNAP,
+                cr: false,
+                control: 3,
+                oui: ZERO_OUI,
+                ethertype: EtherTypes::Aarp.into(),
+            },
+            Aarp {
+                hardware: AarpHardware::Ethernet,
+                protocol: EtherTypes::AppleTalk.into(),
+                hw_address_len: 6,
+                protocol_address_len: 4,
+                function: AarpFunction::Probe,
+                source_hw: self.my_addr_ethernet,
+                _pad1: Default::default(),
+                source_appletalk: addr,
+                destination_hw: ZERO_MAC,
+                _pad2: Default::default(),
+                destination_appletalk: addr,
+            },
+        ))
+    }
+
+    fn write_aarp(&mut self, mut payload: (Elap, Aarp)) -> Result<()> {
+        payload.0.length =
+            <(Elap, Aarp) as PackedStructSlice>::packed_bytes_size(Some(&payload))? as u16 - 14;
+        self.stats.frames_out.aarp += 1;
+        self.stats.aarp_out.count(payload.1.function);
+        self.outbox.push_back(Output::Transmit {
+            port: self.id,
+            frame: payload.pack_to_vec()?.into(),
+        });
+        Ok(())
+    }
+
+    fn write_ddp(&mut self, mut header: (Elap, Ddp), mut payload: PacketBuf) -> Result<()> {
+        let addr = self.address().ok_or(crate::CrabbletalkError::NotAcquired)?;
+        if payload.len() > DDP_MAX_PAYLOAD {
+            return Err(crate::CrabbletalkError::PayloadTooLarge(payload.len()));
+        }
+        header.0.length = (<(Elap, Ddp) as PackedStructSlice>::packed_bytes_size(Some(&header))?
+            - 14
+            + payload.len()) as u16;
+        header.1.set_source(addr);
+        // we only originate datagrams; routers count the hops from here
+        header.1.hop_count = 0;
+        header.1.length = (payload.len()
+            + <Ddp as PackedStructSlice>::packed_bytes_size(Some(&header.1))?)
+            as u16;
+        header.1.set_checksum_from(&payload)?;
+        let header_len = <(Elap, Ddp) as PackedStructSlice>::packed_bytes_size(Some(&header))?;
+        header.pack_to_slice(payload.push_front(header_len))?;
+        let frame = payload;
+        trace!(
+            to = ?header.0.destination,
+            dest = ?header.1.destination(),
+            dest_socket = ?header.1.dest_socket,
+            len = frame.len(),
+            "ddp out"
+        );
+        self.stats.frames_out.appletalk += 1;
+        self.stats.ddp_out += 1;
+        self.outbox.push_back(Output::Transmit {
+            port: self.id,
+            frame,
+        });
+        Ok(())
+    }
+
+    fn write_ddp_to(&mut self, destination: Mac, ddp: Ddp, payload: PacketBuf) -> Result<()> {
+        self.write_ddp(
+            (
                 Elap {
-                    destination: APPLETALK_BROADCAST_MAC,
+                    destination,
                     source: self.my_addr_ethernet,
-                    length: 0,
+                    length: 0, // filled in by write_ddp
                     dsap: SNAP,
                     ig: false,
                     ssap: SNAP,
                     cr: false,
                     control: 3,
-                    oui: ZERO_OUI,
-                    ethertype: EtherTypes::Aarp.into(),
+                    oui: APPLE_OUI,
+                    ethertype: EtherTypes::AppleTalk.into(),
                 },
-                Aarp {
-                    hardware: AarpHardware::Ethernet,
-                    protocol: EtherTypes::AppleTalk.into(),
-                    hw_address_len: 6,
-                    protocol_address_len: 4,
-                    function: AarpFunction::Request,
-                    source_hw: self.my_addr_ethernet,
-                    _pad1: Default::default(),
-                    source_appletalk: addr,
-                    destination_hw: ZERO_MAC,
-                    _pad2: Default::default(),
-                    destination_appletalk: atalk,
-                },
-            ))
-            .await?;
-            Ok(())
-        };
-        tokio::pin!(aarp_fut);
-        loop {
-            tokio::select! {
-                res = rx.changed() => {
-                    res.map_err(|_| crate::CrabbletalkError::Hangup)?;
-                    let potential = rx.borrow_and_update();
-                    if let &Some(AmtEntryCell { hw, .. }) = &*potential {
-                        return Ok(hw);
-                    }
-                }
-                res = &mut aarp_fut, if drive_aarp => {
-                    println!("aarp_fut => {:?}", res);
-                    drive_aarp = false;
-                    return Err(crate::CrabbletalkError::Transient);
-                }
-            }
+                ddp,
+            ),
+            payload,
+        )
+    }
+}
+
+impl AarpStack {
+    /// A stack with one port per config, numbered in order, each of which
+    /// starts probing for an address as of `now`. Fails with `NoPorts` if
+    /// there are no configs, or `InvalidConfig` if any doesn't pass
+    /// [`StackConfig::validate`].
+    pub fn new(
+        configs: impl IntoIterator<Item = StackConfig>,
+        now: Instant,
+        rng: impl RngCore + Send + 'static,
+    ) -> Result<Self> {
+        let configs = configs.into_iter().collect::<Vec<_>>();
+        if configs.is_empty() {
+            return Err(crate::CrabbletalkError::NoPorts);
         }
+        for config in &configs {
+            config.validate()?;
+        }
+        let mut rng: Box<dyn RngCore + Send> = Box::new(rng);
+        let ports = (configs.into_iter().enumerate())
+            .map(|(n, config)| Port::new(PortId(n), config, now, &mut *rng))
+            .collect();
+        Ok(AarpStack {
+            ports,
+            sockets: Default::default(),
+            delivery: Default::default(),
+            outbox: Default::default(),
+            next_send: 0,
+            rng,
+        })
+    }
+
+    fn port(&self, port: PortId) -> Result<&Port> {
+        self.ports
+            .get(port.0)
+            .ok_or(crate::CrabbletalkError::UnknownPort(port))
+    }
+
+    fn port_mut(&mut self, port: PortId) -> Result<&mut Port> {
+        self.ports
+            .get_mut(port.0)
+            .ok_or(crate::CrabbletalkError::UnknownPort(port))
+    }
+
+    fn drop_counters(&self, port: PortId) -> Result<Arc<DropCounters>> {
+        Ok(self.port(port)?.drops.clone())
     }
 
-    pub async fn process_ethernet(&mut self, data: &[u8]) -> Result<()> {
-        let (elap, payload) = Elap::unpack_split(data)?;
+    /// `port`'s accepted address, if it has one.
+    pub fn address(&self, port: PortId) -> Result<Option<Appletalk>> {
+        Ok(self.port(port)?.address())
+    }
+
+    /// What routers have told `port` about its cable, if anything.
+    pub fn network(&self, port: PortId) -> Result<Option<&NetworkInfo>> {
+        Ok(self.port(port)?.network.as_ref())
+    }
+
+    /// Handle a frame that arrived on `port`'s link at `now`.
+    #[tracing::instrument(level = "trace", skip_all, fields(?port, len = frame.len()))]
+    pub fn process_ethernet(&mut self, port: PortId, frame: PacketBuf, now: Instant) -> Result<()> {
+        let p = self
+            .ports
+            .get_mut(port.0)
+            .ok_or(crate::CrabbletalkError::UnknownPort(port))?;
+        let rng = &mut *self.rng;
+        let (elap, payload) = match Elap::unpack_split(&frame) {
+            Ok(x) => x,
+            Err(e) => {
+                p.stats.frames_in.other += 1;
+                return Err(e);
+            }
+        };
         if elap.length > 1600 || elap.dsap != SNAP || elap.ssap != SNAP {
+            p.stats.frames_in.other += 1;
             return Ok(());
         }
         if elap.ethertype == EtherTypes::Aarp {
-            println!("\n==aarp elap: {:#?}", elap);
-            self.process_aarp(payload).await?;
+            trace!(?elap, "aarp frame");
+            p.stats.frames_in.aarp += 1;
+            p.process_aarp(payload, now, rng)?;
         } else if elap.ethertype == EtherTypes::AppleTalk {
-            self.process_ddp(&elap, payload).await?;
+            p.stats.frames_in.appletalk += 1;
+            let payload = frame.slice(ELAP_HEADER_LEN..);
+            let delivery = p.process_ddp(&elap, payload, now, rng)?;
+            if let Some((ddp, payload)) = delivery {
+                self.deliver(port, ddp, payload);
+            }
+        } else {
+            p.stats.frames_in.other += 1;
         }
         Ok(())
     }
 
-    async fn write_aarp(&self, mut payload: (Elap, Aarp)) -> Result<()> {
-        payload.0.length =
-            <(Elap, Aarp) as PackedStructSlice>::packed_bytes_size(Some(&payload))? as u16 - 14;
-        self.appletalk_tx
-            .send(AppletalkPacket(payload.pack_to_vec()?))
-            .await
-            .map_err(|_| crate::CrabbletalkError::Hangup)
-    }
-
-    async fn write_ddp(&self, mut header: (Elap, Ddp), payload: &[u8]) -> Result<()> {
-        println!("write_ddp: {:#?}", header);
-        let addr = match &self.phase {
-            AddressPhase::Accepted { addr, .. } => addr,
-            _ => {
-                println!("erk");
-                return Ok(());
+    /// Hand a datagram that arrived on `port` to the socket bound there.
+    fn deliver(&mut self, port: PortId, ddp: Ddp, payload: PacketBuf) {
+        let socket = ddp.dest_socket;
+        let stats = match self.sockets.get_mut(port, socket) {
+            Some(s) => s,
+            None => {
+                debug!(?port, ?socket, "nobody bound");
+                self.delivery.no_socket += 1;
+                return;
             }
         };
-        header.0.length = (<(Elap, Ddp) as PackedStructSlice>::packed_bytes_size(Some(&header))?
-            - 14
-            + payload.len()) as u16;
-        header.1.set_source(*addr);
-        header.1.length = (payload.len()
-            + <Ddp as PackedStructSlice>::packed_bytes_size(Some(&header.1))?)
-            as u16;
-        let mut payload_vec = header.pack_to_vec()?;
-        payload_vec.extend_from_slice(payload);
-        println!("out to the wire? {:?}", payload_vec);
-        let res = self.appletalk_tx
-            .send(AppletalkPacket(payload_vec))
-            .await
-            .map_err(|_| crate::CrabbletalkError::Hangup);
-        println!("  .. ok?");
-        res
-    }
-
-    async fn maybe_probe_phase(&self, just_set: bool) -> Result<()> {
-        match &self.phase {
-            AddressPhase::Accepted { addr } if just_set => {
-                self.my_addr_appletalk_tx.send_replace(Some(*addr));
-            }
-            AddressPhase::Tentative { addr, .. } => {
-                let addr = *addr;
-                self.write_aarp((
-                    Elap {
-                        destination: APPLETALK_BROADCAST_MAC,
-                        source: self.my_addr_ethernet,
-                        length: 0,
-                        dsap: SNAP,
-                        ig: false,
-                        ssap: SNAP,
-                        cr: false,
-                        control: 3,
-                        oui: ZERO_OUI,
-                        ethertype: EtherTypes::Aarp.into(),
-                    },
-                    Aarp {
-                        hardware: AarpHardware::Ethernet,
-                        protocol: EtherTypes::AppleTalk.into(),
-                        hw_address_len: 6,
-                        protocol_address_len: 4,
-                        function: AarpFunction::Probe,
-                        source_hw: self.my_addr_ethernet,
-                        _pad1: Default::default(),
-                        source_appletalk: addr,
-                        destination_hw: ZERO_MAC,
-                        _pad2: Default::default(),
-                        destination_appletalk: addr,
-                    },
-                ))
-                .await?;
-            }
-            _ => {}
+        stats.datagrams_in += 1;
+        stats.bytes_in += payload.len() as u64;
+        self.delivery.delivered += 1;
+        self.outbox.push_back(Output::Deliver {
+            port,
+            socket,
+            header: ddp,
+            payload,
+        });
+    }
+
+    /// The driver couldn't hand over a `Deliver`, because the socket's queue
+    /// was full.
+    pub fn delivery_dropped(&mut self, port: PortId, socket: AppletalkSocket, len: usize) {
+        if let Some(stats) = self.sockets.get_mut(port, socket) {
+            stats.datagrams_in = stats.datagrams_in.saturating_sub(1);
+            stats.bytes_in = stats.bytes_in.saturating_sub(len as u64);
+            stats.dropped += 1;
         }
-        Ok(())
+        self.delivery.delivered = self.delivery.delivered.saturating_sub(1);
+        self.delivery.socket_full += 1;
     }
 
-    pub async fn next_address(&self) -> Result<Appletalk> {
-        let mut rx = tokio_stream::wrappers::WatchStream::new(self.my_addr_appletalk_rx.clone());
-        loop {
-            match rx.next().await {
-                Some(Some(x)) => return Ok(x),
-                Some(None) => continue,
-                None => return Err(crate::CrabbletalkError::Hangup),
-            }
+    /// Send out of the port the sending socket is bound to if the
+    /// destination's network is local to it, or else whichever port's is,
+    /// falling back to the home port. Without routers every port thinks the
+    /// whole startup range is local, so the home port has to come first.
+    fn route(&self, dest: Appletalk, home: PortId) -> PortId {
+        let local = |p: &Port| p.is_local_net(dest.net);
+        if dest.net == 0 || self.ports.get(home.0).is_some_and(local) {
+            return home;
         }
+        self.ports
+            .iter()
+            .position(local)
+            .map(PortId)
+            .unwrap_or(home)
     }
 
-    pub async fn spawn(
-        mut self,
-        mut buffer_rx: mpsc::Receiver<Vec<u8>>,
-        mut ddp_control_rx: mpsc::Receiver<DdpControl>,
-    ) {
-        let (phase_tx, mut phase_rx) = mpsc::channel(1);
-        let mut phase_fut = AddressPhase::acquire(phase_tx.clone());
-        tokio::pin!(phase_fut);
-        let mut drive_phase_fut = true;
-        let mut join_set = tokio::task::JoinSet::new();
-        let mut ddp_merge = tokio_stream::StreamMap::new();
-        loop {
-            tokio::select! {
-                res = &mut phase_fut, if drive_phase_fut => {
-                    println!("what phase my man {:?}", res);
-                    drive_phase_fut = false;
-                }
-                next = phase_rx.recv() => {
-                    self.phase = match next {
-                        Some(p) => p,
-                        None => {
-                            println!("phase rx abort");
-                            return;
-                        },
-                    };
-                    let res = self.maybe_probe_phase(true).await;
-                    println!("stepped phase: {:#?} {:?}", self, res);
-                }
-                next = buffer_rx.recv() => {
-                    let buf = match next {
-                        Some(b) => b,
-                        None => {
-                            println!("buffer rx abort");
-                            return;
-                        },
-                    };
-                    let res = self.process_ethernet(&buf[..]).await;
-                    println!("did some ethernet: {:?}", res);
-                }
-                next = ddp_control_rx.recv() => {
-                    let ctrl = match next {
-                        Some(x) => x,
-                        None => {
-                            println!("ddp control rx abort");
-                            return;
-                        },
-                    };
-                    let (ddp_tx_in, ddp_rx_in) = mpsc::channel::<(Ddp, Vec<u8>)>(1);
-                    let (ddp_tx_out, ddp_rx_out) = mpsc::channel::<(Ddp, Vec<u8>)>(1);
-                    ddp_merge.insert(ctrl.bind, tokio_stream::wrappers::ReceiverStream::new(ddp_rx_in));
-                    let mut addr_stream = tokio_stream::wrappers::WatchStream::new(self.my_addr_appletalk_rx.clone());
-                    //let addr_fut = self.next_address();
-                    join_set.spawn(async move {
-                        let addr = loop {
-                            println!("spin, spin,");
-                            match addr_stream.next().await {
-                                Some(Some(x)) => break x,
-                                Some(None) => continue,
-                                None => return Err(crate::CrabbletalkError::Hangup),
-                            }
-                        };
-                        let ret = crate::ddp::DdpSocket { addr, socket: ctrl.bind, ddp_tx: ddp_tx_in, ddp_rx: ddp_rx_out };
-                        println!("aight so we got {:#?}", ret);
-                        let _ = ctrl.reply.send(ret);
-                        Result::<_>::Ok(())
-                    });
-                }
-                next = join_set.join_one(), if !join_set.is_empty() => {
-                    println!("whoa stream step: {:?}", next);
-                }
-                next = ddp_merge.next(), if !ddp_merge.is_empty() => {
-                    let (socket, (ddp, payload)) = match next {
-                        Some(x) => x,
-                        None => {
-                            println!("ddp merge abort");
-                            return;
-                        },
-                    };
-                    let destination = match self.hw_from_appletalk(ddp.destination()).await {
-                        Ok(x) => x,
-                        Err(e) => {
-                            println!("hw4a failure {:?}", e);
-                            continue;
-                        }
-                    };
-                    let res = self.write_ddp(
-                        (
-                            Elap {
-                                destination,
-                                source: self.my_addr_ethernet,
-                                length: 0,  // filled in by write_ddp
-                                dsap: SNAP,
-                                ig: false,
-                                ssap: SNAP,
-                                cr: false,
-                                control: 3,
-                                oui: APPLE_OUI,
-                                ethertype: EtherTypes::AppleTalk.into(),
-                            },
-                            ddp,
-                        ),
-                        &payload[..],
-                    )
-                    .await;
-                }
-                () = tokio::time::sleep(std::time::Duration::from_millis(100)) => {
-                    match self.maybe_probe_phase(false).await {
-                        Ok(()) => {}
-                        Err(e) => {
-                            println!("sent another probe? {:?}", e);
-                        }
-                    }
-                }
+    /// Send a datagram from a socket bound on `home`. Its outcome comes out
+    /// of `poll_output` as an `Output::Sent` with the returned id, possibly
+    /// only after AARP has resolved the destination.
+    pub fn send_ddp(&mut self, home: PortId, out: DdpOutbound, now: Instant) -> SendId {
+        let id = SendId(self.next_send);
+        self.next_send += 1;
+        if let Some(stats) = self.sockets.get_mut(home, out.header.src_socket) {
+            stats.datagrams_out += 1;
+            stats.bytes_out += out.payload.len() as u64;
+        }
+        let dest = out.header.destination();
+        if let Some(port) = self.ports.iter().position(|p| p.address() == Some(dest)) {
+            self.loopback(PortId(port), out.header, out.payload, dest);
+            self.outbox.push_back(Output::Sent { id, result: Ok(()) });
+            return id;
+        }
+        let port = self.route(dest, home);
+        if dest.node == AppletalkNode::Broadcast && out.broadcast {
+            let source = self.ports.get(port.0).and_then(|p| {
+                let local = dest.net == 0 || p.is_local_net(dest.net);
+                let in_zone = out.zone.as_deref().is_none_or(|z| p.in_zone(z));
+                p.address().filter(|_| local && in_zone)
+            });
+            if let Some(source) = source {
+                self.loopback(port, out.header.clone(), out.payload.clone(), source);
             }
         }
+        match self.port_mut(port) {
+            Ok(p) => p.send_ddp(id, out, now),
+            Err(e) => self.outbox.push_back(Output::Sent { id, result: Err(e) }),
+        }
+        id
     }
-}
 
-pub struct DdpControl {
-    bind: AppletalkSocket,
-    reply: oneshot::Sender<DdpSocket>,
-}
+    /// Hand a datagram we sent straight to our own sockets on `port`, as if
+    /// it had come in from `source`.
+    fn loopback(&mut self, port: PortId, mut header: Ddp, payload: PacketBuf, source: Appletalk) {
+        header.set_source(source);
+        header.length = (DDP_HEADER_LEN + payload.len()) as u16;
+        self.delivery.loopback += 1;
+        self.deliver(port, header, payload);
+    }
 
-#[derive(Debug, Clone)]
-pub struct AarpStackHandle {
-    buffer_tx: mpsc::Sender<Vec<u8>>,
-    control_tx: mpsc::Sender<DdpControl>,
-}
-
-impl AarpStackHandle {
-    pub fn spawn(hw: Mac) -> (Self, mpsc::Receiver<AppletalkPacket>) {
-        let (stack, appletalk_rx) = AarpStack::new(hw);
-        let (buffer_tx, buffer_rx) = mpsc::channel(1);
-        let (control_tx, control_rx) = mpsc::channel(1);
-        let handle = task::spawn(stack.spawn(buffer_rx, control_rx));
-        (
-            Self {
-                buffer_tx,
-                control_tx,
-            },
-            appletalk_rx,
-        )
+    /// Bind `socket` at `port`'s address, or any dynamic socket free there if
+    /// `None`. Fails with `NotAcquired` while the port is still probing, or
+    /// `SocketInUse` if `socket` is already bound on `port`.
+    pub fn bind(
+        &mut self,
+        port: PortId,
+        socket: Option<AppletalkSocket>,
+    ) -> Result<(AppletalkSocket, Appletalk)> {
+        let addr = match self.port(port)?.phase {
+            AddressPhase::Accepted { addr } => addr,
+            AddressPhase::Failed { conflicts } => {
+                return Err(crate::CrabbletalkError::AddressConflicts(conflicts))
+            }
+            AddressPhase::Tentative(_) => return Err(crate::CrabbletalkError::NotAcquired),
+        };
+        let socket = match socket {
+            Some(socket) => socket,
+            None => self.sockets.allocate_dynamic(port, &mut *self.rng)?,
+        };
+        self.sockets.insert(port, socket)?;
+        Ok((socket, addr))
     }
 
-    pub async fn process_ethernet(&self, data: &[u8]) -> Result<()> {
-        self.buffer_tx
-            .send(data.to_owned())
-            .await
-            .map_err(|_| crate::CrabbletalkError::Hangup)
+    /// Free a socket on `port` to be bound again.
+    pub fn unbind(&mut self, port: PortId, socket: AppletalkSocket) {
+        self.sockets.remove(port, socket);
     }
 
-    pub async fn open_ddp(&self, bind: AppletalkSocket) -> Result<DdpSocket> {
-        let (tx, rx) = oneshot::channel();
-        self.control_tx
-            .send(DdpControl { bind, reply: tx })
-            .await
-            .map_err(|_| crate::CrabbletalkError::Hangup)?;
-        Ok(rx.await.map_err(|_| crate::CrabbletalkError::Hangup)?)
+    /// When `on_timer` next needs calling, if ever.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.ports.iter().filter_map(Port::next_deadline).min()
+    }
+
+    pub fn on_timer(&mut self, now: Instant) {
+        for port in &mut self.ports {
+            port.on_timer(now);
+        }
+    }
+
+    /// The next thing the stack wants done, if there is one.
+    pub fn poll_output(&mut self) -> Option<Output> {
+        if let Some(out) = self.outbox.pop_front() {
+            return Some(out);
+        }
+        self.ports.iter_mut().find_map(Port::poll_output)
+    }
+
+    pub fn snapshot(&self) -> StackSnapshot {
+        StackSnapshot {
+            ports: self.ports.iter().map(Port::snapshot).collect(),
+            sockets: self.sockets.snapshot(),
+            delivery: self.delivery,
+        }
     }
 }
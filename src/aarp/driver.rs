@@ -0,0 +1,496 @@
+// © 2022 <_@habnab.it>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Runs an [`AarpStack`] on tokio: frames, binds and datagrams come in over
+//! channels, and whatever the stack puts out goes back over them.
+
+use std::{collections::BTreeMap, future::Future, sync::Arc, time::Instant};
+
+use rand::RngCore;
+use tokio::{
+    sync::{broadcast, mpsc, oneshot, watch},
+    task,
+};
+use tokio_stream::{
+    wrappers::{BroadcastStream, ReceiverStream},
+    StreamExt, StreamMap,
+};
+use tracing::{debug, trace, warn, Instrument};
+
+use super::{
+    AarpStack, NetworkInfo, Output, PortId, SendId, StackConfig, StackEvent, StackEventKind,
+};
+use crate::{
+    addr::{Appletalk, AppletalkSocket},
+    buf::PacketBuf,
+    ddp::{Ddp, DdpSocket, DropCounters, Outgoing},
+    link::AppletalkPacket,
+    stats::StackSnapshot,
+    Result,
+};
+
+/// Where the driver gets the time it hands the stack, and how it waits for
+/// the stack's next deadline.
+pub trait Clock: Send + 'static {
+    type Sleep: Future<Output = ()> + Send;
+
+    fn now(&self) -> Instant;
+
+    fn sleep_until(&self, deadline: Instant) -> Self::Sleep;
+}
+
+/// tokio's clock, so pausing the runtime pauses the stack too.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    type Sleep = tokio::time::Sleep;
+
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Self::Sleep {
+        tokio::time::sleep_until(deadline.into())
+    }
+}
+
+pub struct DdpControl {
+    port: PortId,
+    /// `None` to allocate a dynamic socket.
+    bind: Option<AppletalkSocket>,
+    reply: oneshot::Sender<Result<DdpSocket>>,
+}
+
+/// Requests from handles to the stack's task.
+pub enum StackControl {
+    Bind(DdpControl),
+    Snapshot(oneshot::Sender<StackSnapshot>),
+}
+
+/// The `DdpSocket` end of each bound socket, by port, and the merged stream
+/// of what they send. A socket whose `DdpSocket` was dropped is free to bind
+/// again.
+#[derive(Default)]
+struct SocketChannels {
+    inbound: BTreeMap<(PortId, AppletalkSocket), mpsc::Sender<(Ddp, PacketBuf)>>,
+    outbound: StreamMap<(PortId, AppletalkSocket), ReceiverStream<Outgoing>>,
+}
+
+struct Driver<C> {
+    stack: AarpStack,
+    clock: C,
+    links: Vec<mpsc::Sender<AppletalkPacket>>,
+    addr_tx: Vec<watch::Sender<Option<Appletalk>>>,
+    network_tx: Vec<watch::Sender<Option<NetworkInfo>>>,
+    events: broadcast::Sender<StackEvent>,
+    socket_queue_depth: Vec<usize>,
+    sockets: SocketChannels,
+    sent: BTreeMap<SendId, oneshot::Sender<Result<()>>>,
+    waiting_binds: Vec<DdpControl>,
+}
+
+impl<C: Clock> Driver<C> {
+    async fn run(
+        mut self,
+        mut buffer_rx: mpsc::Receiver<(PortId, PacketBuf)>,
+        mut control_rx: mpsc::Receiver<StackControl>,
+    ) {
+        loop {
+            self.flush().await;
+            let deadline = self.stack.next_deadline();
+            let sleep = self
+                .clock
+                .sleep_until(deadline.unwrap_or_else(|| self.clock.now()));
+            tokio::select! {
+                next = buffer_rx.recv() => {
+                    let (port, buf) = match next {
+                        Some(b) => b,
+                        None => {
+                            debug!("input channel closed; stopping");
+                            return;
+                        },
+                    };
+                    if let Err(e) = self.stack.process_ethernet(port, buf, self.clock.now()) {
+                        debug!(?port, error = ?e, "bad frame");
+                    }
+                }
+                next = control_rx.recv() => {
+                    match next {
+                        Some(StackControl::Bind(ctrl)) => self.open_ddp(ctrl),
+                        Some(StackControl::Snapshot(reply)) => {
+                            self.reap();
+                            let _ = reply.send(self.stack.snapshot());
+                        }
+                        None => {
+                            debug!("control channel closed; stopping");
+                            return;
+                        },
+                    }
+                }
+                next = self.sockets.outbound.next(), if !self.sockets.outbound.is_empty() => {
+                    // `None` only means the last socket was dropped
+                    let ((port, socket), out) = match next {
+                        Some(x) => x,
+                        None => continue,
+                    };
+                    let now = self.clock.now();
+                    let id = tracing::trace_span!("send", ?port, ?socket)
+                        .in_scope(|| self.stack.send_ddp(port, out.datagram, now));
+                    self.sent.insert(id, out.sent);
+                }
+                () = sleep, if deadline.is_some() => {
+                    self.stack.on_timer(self.clock.now());
+                }
+            }
+        }
+    }
+
+    /// Carry out everything the stack has asked for.
+    async fn flush(&mut self) {
+        while let Some(output) = self.stack.poll_output() {
+            match output {
+                Output::Transmit { port, frame } => {
+                    if self.links[port.0]
+                        .send(AppletalkPacket(frame))
+                        .await
+                        .is_err()
+                    {
+                        debug!(?port, "link went away; dropping frame");
+                    }
+                }
+                Output::Deliver {
+                    port,
+                    socket,
+                    header,
+                    payload,
+                } => self.deliver(port, socket, header, payload),
+                Output::Sent { id, result } => {
+                    if let Some(sent) = self.sent.remove(&id) {
+                        let _ = sent.send(result);
+                    }
+                }
+                Output::Event(event) => self.event(event),
+            }
+        }
+    }
+
+    fn deliver(&mut self, port: PortId, socket: AppletalkSocket, header: Ddp, payload: PacketBuf) {
+        let tx = match self.sockets.inbound.get(&(port, socket)) {
+            Some(tx) => tx,
+            None => return,
+        };
+        let len = payload.len();
+        match tx.try_send((header, payload)) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                warn!(?port, ?socket, "socket is backed up; dropping");
+                self.stack.delivery_dropped(port, socket, len);
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                debug!(?port, ?socket, "socket went away");
+                self.unbind(port, socket);
+            }
+        }
+    }
+
+    fn event(&mut self, event: StackEvent) {
+        let port = event.port;
+        trace!(?event, "event");
+        match &event.kind {
+            StackEventKind::AddressAcquired(addr) => {
+                self.addr_tx[port.0].send_replace(Some(*addr));
+                self.retry_binds(port);
+            }
+            StackEventKind::AddressLost { .. } | StackEventKind::AddressOutOfRange { .. } => {
+                self.addr_tx[port.0].send_replace(None);
+            }
+            StackEventKind::AcquisitionFailed { .. } => self.retry_binds(port),
+            StackEventKind::NetworkChanged(info) => {
+                self.network_tx[port.0].send_replace(Some(info.clone()));
+            }
+            _ => {}
+        }
+        let _ = self.events.send(event);
+    }
+
+    fn retry_binds(&mut self, port: PortId) {
+        let (waiting, rest) = std::mem::take(&mut self.waiting_binds)
+            .into_iter()
+            .partition(|c| c.port == port);
+        self.waiting_binds = rest;
+        for ctrl in waiting {
+            self.open_ddp(ctrl);
+        }
+    }
+
+    fn open_ddp(&mut self, ctrl: DdpControl) {
+        self.reap();
+        let (socket, addr) = match self.stack.bind(ctrl.port, ctrl.bind) {
+            Ok(bound) => bound,
+            Err(crate::CrabbletalkError::NotAcquired) => {
+                self.waiting_binds.push(ctrl);
+                return;
+            }
+            Err(e) => {
+                debug!(port = ?ctrl.port, socket = ?ctrl.bind, error = ?e, "bind failed");
+                let _ = ctrl.reply.send(Err(e));
+                return;
+            }
+        };
+        let (ddp_tx_in, ddp_rx_in) = mpsc::channel(1);
+        let (ddp_tx_out, ddp_rx_out) = mpsc::channel(self.socket_queue_depth[ctrl.port.0]);
+        let ret = DdpSocket::new(
+            addr,
+            self.addr_tx[ctrl.port.0].subscribe(),
+            socket,
+            ddp_tx_in,
+            ddp_rx_out,
+        );
+        self.sockets.inbound.insert((ctrl.port, socket), ddp_tx_out);
+        self.sockets
+            .outbound
+            .insert((ctrl.port, socket), ReceiverStream::new(ddp_rx_in));
+        debug!(port = ?ctrl.port, ?socket, ?addr, "bound");
+        let _ = ctrl.reply.send(Ok(ret));
+    }
+
+    /// Unbind sockets whose `DdpSocket` has been dropped.
+    fn reap(&mut self) {
+        let closed = (self.sockets.inbound.iter())
+            .filter(|(_, tx)| tx.is_closed())
+            .map(|(&s, _)| s)
+            .collect::<Vec<_>>();
+        for (port, socket) in closed {
+            self.unbind(port, socket);
+        }
+    }
+
+    fn unbind(&mut self, port: PortId, socket: AppletalkSocket) {
+        self.sockets.inbound.remove(&(port, socket));
+        self.sockets.outbound.remove(&(port, socket));
+        self.stack.unbind(port, socket);
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PortWatch {
+    my_addr_appletalk_rx: watch::Receiver<Option<Appletalk>>,
+    network_rx: watch::Receiver<Option<NetworkInfo>>,
+    drops: Arc<DropCounters>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AarpStackHandle {
+    buffer_tx: mpsc::Sender<(PortId, PacketBuf)>,
+    control_tx: mpsc::Sender<StackControl>,
+    events: broadcast::Sender<StackEvent>,
+    /// Never empty, which the first-port getters rely on.
+    ports: Vec<PortWatch>,
+}
+
+impl AarpStackHandle {
+    pub fn spawn(
+        config: impl Into<StackConfig>,
+    ) -> Result<(Self, mpsc::Receiver<AppletalkPacket>)> {
+        let (handle, mut links) = Self::spawn_ports([config.into()])?;
+        Ok((handle, links.remove(0)))
+    }
+
+    /// Spawn a stack with a port on each of several links, returning each
+    /// port's outbound frames in the same order as `configs`. Queue depths
+    /// shared between ports are the largest any port asks for. Fails with
+    /// `NoPorts` if `configs` is empty, or `InvalidConfig` if any config
+    /// doesn't pass [`StackConfig::validate`].
+    pub fn spawn_ports(
+        configs: impl IntoIterator<Item = StackConfig>,
+    ) -> Result<(Self, Vec<mpsc::Receiver<AppletalkPacket>>)> {
+        Self::spawn_with(configs, TokioClock, rand::rngs::OsRng)
+    }
+
+    /// Like `spawn_ports`, with the stack's time and randomness coming from
+    /// `clock` and `rng`.
+    pub fn spawn_with(
+        configs: impl IntoIterator<Item = StackConfig>,
+        clock: impl Clock,
+        rng: impl RngCore + Send + 'static,
+    ) -> Result<(Self, Vec<mpsc::Receiver<AppletalkPacket>>)> {
+        let configs = configs.into_iter().collect::<Vec<_>>();
+        // before any channel, which would panic on a zero depth
+        for config in &configs {
+            config.validate()?;
+        }
+        let depth = |f: fn(&StackConfig) -> usize| configs.iter().map(f).max().unwrap_or(1);
+        let (buffer_tx, buffer_rx) = mpsc::channel(depth(|c| c.input_queue_depth));
+        let (control_tx, control_rx) = mpsc::channel(depth(|c| c.control_queue_depth));
+        let (events, _) = broadcast::channel(depth(|c| c.event_queue_depth));
+        let (links, link_rxs): (Vec<_>, Vec<_>) = configs
+            .iter()
+            .map(|c| mpsc::channel(c.link_queue_depth))
+            .unzip();
+        let (addr_tx, addr_rx): (Vec<_>, Vec<_>) =
+            configs.iter().map(|_| watch::channel(None)).unzip();
+        let (network_tx, network_rx): (Vec<_>, Vec<_>) =
+            configs.iter().map(|_| watch::channel(None)).unzip();
+        let socket_queue_depth = configs.iter().map(|c| c.socket_queue_depth).collect();
+        let hw = configs.iter().map(|c| c.hw).collect::<Vec<_>>();
+        let stack = AarpStack::new(configs, clock.now(), rng)?;
+        let ports = (addr_rx.into_iter().zip(network_rx).enumerate())
+            .map(|(n, (my_addr_appletalk_rx, network_rx))| PortWatch {
+                my_addr_appletalk_rx,
+                network_rx,
+                drops: stack
+                    .drop_counters(PortId(n))
+                    .expect("every config has a port"),
+            })
+            .collect();
+        let driver = Driver {
+            stack,
+            clock,
+            links,
+            addr_tx,
+            network_tx,
+            events: events.clone(),
+            socket_queue_depth,
+            sockets: Default::default(),
+            sent: Default::default(),
+            waiting_binds: Default::default(),
+        };
+        let span = tracing::info_span!("aarp_stack", ?hw);
+        task::spawn(driver.run(buffer_rx, control_rx).instrument(span));
+        Ok((
+            Self {
+                buffer_tx,
+                control_tx,
+                events,
+                ports,
+            },
+            link_rxs,
+        ))
+    }
+
+    fn port(&self, port: PortId) -> Result<&PortWatch> {
+        self.ports
+            .get(port.0)
+            .ok_or(crate::CrabbletalkError::UnknownPort(port))
+    }
+
+    /// Everything that happens on any port from now on. A subscriber that
+    /// falls more than `event_queue_depth` behind gets a `Lagged` error and
+    /// skips ahead.
+    pub fn events(&self) -> BroadcastStream<StackEvent> {
+        BroadcastStream::new(self.events.subscribe())
+    }
+
+    /// The first port's accepted address, if it has one.
+    pub fn address(&self) -> Option<Appletalk> {
+        *self.ports[0].my_addr_appletalk_rx.borrow()
+    }
+
+    pub fn address_on(&self, port: PortId) -> Result<Option<Appletalk>> {
+        Ok(*self.port(port)?.my_addr_appletalk_rx.borrow())
+    }
+
+    /// What routers have told the first port about its cable, if anything.
+    pub fn network(&self) -> Option<NetworkInfo> {
+        self.ports[0].network_rx.borrow().clone()
+    }
+
+    pub fn network_on(&self, port: PortId) -> Result<Option<NetworkInfo>> {
+        Ok(self.port(port)?.network_rx.borrow().clone())
+    }
+
+    /// Watch the first port's accepted address, which is `None` until
+    /// acquisition completes.
+    pub fn watch_address(&self) -> watch::Receiver<Option<Appletalk>> {
+        self.ports[0].my_addr_appletalk_rx.clone()
+    }
+
+    pub fn watch_address_on(&self, port: PortId) -> Result<watch::Receiver<Option<Appletalk>>> {
+        Ok(self.port(port)?.my_addr_appletalk_rx.clone())
+    }
+
+    /// Watch what routers have told the first port about its cable, which is
+    /// `None` until one is heard from.
+    pub fn watch_network(&self) -> watch::Receiver<Option<NetworkInfo>> {
+        self.ports[0].network_rx.clone()
+    }
+
+    pub fn watch_network_on(&self, port: PortId) -> Result<watch::Receiver<Option<NetworkInfo>>> {
+        Ok(self.port(port)?.network_rx.clone())
+    }
+
+    /// How many inbound datagrams the first port has thrown away as invalid.
+    pub fn drop_counters(&self) -> Arc<DropCounters> {
+        self.ports[0].drops.clone()
+    }
+
+    pub fn drop_counters_on(&self, port: PortId) -> Result<Arc<DropCounters>> {
+        Ok(self.port(port)?.drops.clone())
+    }
+
+    pub async fn process_ethernet(&self, frame: impl Into<PacketBuf>) -> Result<()> {
+        self.process_ethernet_on(PortId::default(), frame).await
+    }
+
+    /// Hand the stack a frame from `port`'s link. A `Vec` or `PacketBuf` is
+    /// passed along as is; a slice is copied.
+    pub async fn process_ethernet_on(
+        &self,
+        port: PortId,
+        frame: impl Into<PacketBuf>,
+    ) -> Result<()> {
+        self.buffer_tx
+            .send((port, frame.into()))
+            .await
+            .map_err(|_| crate::CrabbletalkError::Hangup)
+    }
+
+    /// Bind `bind` on the first port, failing with `SocketInUse` if it's
+    /// already bound there.
+    pub async fn open_ddp(&self, bind: AppletalkSocket) -> Result<DdpSocket> {
+        self.open_ddp_on(PortId::default(), bind).await
+    }
+
+    /// Bind whichever dynamic socket is free on the first port.
+    pub async fn open_ddp_dynamic(&self) -> Result<DdpSocket> {
+        self.open_ddp_dynamic_on(PortId::default()).await
+    }
+
+    /// Bind a socket whose address is `port`'s. Each port has sockets of its
+    /// own, so this only fails with `SocketInUse` if `bind` is already bound
+    /// on `port`, and the socket only hears datagrams that arrive there.
+    /// Datagrams it sends go out of `port` if the destination's network is
+    /// local to it, and otherwise out of whichever port's is.
+    pub async fn open_ddp_on(&self, port: PortId, bind: AppletalkSocket) -> Result<DdpSocket> {
+        self.bind(port, Some(bind)).await
+    }
+
+    pub async fn open_ddp_dynamic_on(&self, port: PortId) -> Result<DdpSocket> {
+        self.bind(port, None).await
+    }
+
+    async fn bind(&self, port: PortId, bind: Option<AppletalkSocket>) -> Result<DdpSocket> {
+        let (tx, rx) = oneshot::channel();
+        self.control_tx
+            .send(StackControl::Bind(DdpControl {
+                port,
+                bind,
+                reply: tx,
+            }))
+            .await
+            .map_err(|_| crate::CrabbletalkError::Hangup)?;
+        rx.await.map_err(|_| crate::CrabbletalkError::Hangup)?
+    }
+
+    /// Counters, addresses and bound sockets for every port, as of now.
+    pub async fn snapshot(&self) -> Result<StackSnapshot> {
+        let (tx, rx) = oneshot::channel();
+        self.control_tx
+            .send(StackControl::Snapshot(tx))
+            .await
+            .map_err(|_| crate::CrabbletalkError::Hangup)?;
+        rx.await.map_err(|_| crate::CrabbletalkError::Hangup)
+    }
+}
@@ -47,9 +47,12 @@ impl fmt::Debug for Mac {
 
 impl Mac {
     pub fn new_random() -> Self {
-        use rand::{rngs::OsRng, Rng};
+        Self::new_random_from(&mut rand::rngs::OsRng)
+    }
+
+    pub fn new_random_from<R: rand::Rng + ?Sized>(rng: &mut R) -> Self {
         let mut nic = [0u8; 3];
-        OsRng.fill(&mut nic[..]);
+        rng.fill(&mut nic[..]);
         Mac { oui: LAA_OUI, nic }
     }
 }
@@ -129,11 +132,33 @@ pub struct Appletalk {
 impl Appletalk {
     pub fn new_random() -> Self {
         //return Appletalk { net: 0xff00, node: AppletalkNode::Node(0x80) };
-        use rand::{rngs::OsRng, Rng};
-        let net = OsRng.gen_range(APPLETALK_STARTUP_NET_RANGE);
-        let node = AppletalkNode::Node(OsRng.gen_range(APPLETALK_ANY_NODE_RANGE));
+        Self::new_random_in(APPLETALK_STARTUP_NET_RANGE, APPLETALK_ANY_NODE_RANGE)
+    }
+
+    pub fn new_random_in(nets: RangeInclusive<u16>, nodes: RangeInclusive<u8>) -> Self {
+        Self::new_random_from(&mut rand::rngs::OsRng, nets, nodes)
+    }
+
+    pub fn new_random_from<R: rand::Rng + ?Sized>(
+        rng: &mut R,
+        nets: RangeInclusive<u16>,
+        nodes: RangeInclusive<u8>,
+    ) -> Self {
+        let net = rng.gen_range(nets);
+        let node = AppletalkNode::Node(rng.gen_range(nodes));
         Appletalk { net, node }
     }
+
+    /// Whether this could be assigned to a single node, i.e. it is neither a
+    /// broadcast nor in the reserved nets.
+    pub fn is_node_address(&self) -> bool {
+        match self.node {
+            AppletalkNode::Node(n) => {
+                APPLETALK_ANY_NODE_RANGE.contains(&n) && self.net != 0 && self.net != 0xFFFF
+            }
+            _ => false,
+        }
+    }
 }
 
 impl fmt::Debug for Appletalk {
@@ -153,8 +178,10 @@ pub const APPLETALK_DDP_DAS_RANGE: RangeInclusive<u8> = 0x80..=0xFD;
 #[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum AppletalkSocketPrim {
     Reserved0 = 0,
+    SasRtmp = 1,
     SasNbp = 2,
     SasAep = 4,
+    SasZip = 6,
     Reserved255 = 255,
 }
 
@@ -162,8 +189,10 @@ pub type AppletalkSocketCatchall = EnumCatchAll<AppletalkSocketPrim>;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Sas {
+    Rtmp,
     Nbp,
     Aep,
+    Zip,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -176,10 +205,18 @@ pub enum AppletalkSocket {
 }
 
 impl AppletalkSocket {
+    pub const RTMP: Self = AppletalkSocket::StaticSas(Sas::Rtmp);
+    pub const NBP: Self = AppletalkSocket::StaticSas(Sas::Nbp);
+    /// Where AEP echo requests go.
+    pub const ECHO: Self = AppletalkSocket::StaticSas(Sas::Aep);
+    pub const ZIP: Self = AppletalkSocket::StaticSas(Sas::Zip);
+
     pub fn new_random_dynamic() -> Self {
-        use rand::{rngs::OsRng, Rng};
-        let n = OsRng.gen_range(APPLETALK_DDP_DAS_RANGE);
-        AppletalkSocket::Dynamic(n)
+        Self::new_random_dynamic_from(&mut rand::rngs::OsRng)
+    }
+
+    pub fn new_random_dynamic_from<R: rand::Rng + ?Sized>(rng: &mut R) -> Self {
+        AppletalkSocket::Dynamic(rng.gen_range(APPLETALK_DDP_DAS_RANGE))
     }
 
     fn from_prim(val: AppletalkSocketCatchall) -> Self {
@@ -187,8 +224,10 @@ impl AppletalkSocket {
         match val {
             Enum(Reserved0) => AppletalkSocket::Reserved0,
             Enum(Reserved255) => AppletalkSocket::Reserved255,
+            Enum(SasRtmp) => AppletalkSocket::StaticSas(Sas::Rtmp),
             Enum(SasNbp) => AppletalkSocket::StaticSas(Sas::Nbp),
             Enum(SasAep) => AppletalkSocket::StaticSas(Sas::Aep),
+            Enum(SasZip) => AppletalkSocket::StaticSas(Sas::Zip),
             CatchAll(e @ 0x7f..=0xfe) => AppletalkSocket::Dynamic(e),
             CatchAll(e) => AppletalkSocket::Static(e),
         }
@@ -199,8 +238,10 @@ impl AppletalkSocket {
         match self {
             Reserved0 => Enum(AppletalkSocketPrim::Reserved0),
             Reserved255 => Enum(AppletalkSocketPrim::Reserved255),
+            StaticSas(Sas::Rtmp) => Enum(AppletalkSocketPrim::SasRtmp),
             StaticSas(Sas::Nbp) => Enum(AppletalkSocketPrim::SasNbp),
             StaticSas(Sas::Aep) => Enum(AppletalkSocketPrim::SasAep),
+            StaticSas(Sas::Zip) => Enum(AppletalkSocketPrim::SasZip),
             Static(e) | Dynamic(e) => CatchAll(e),
         }
     }
@@ -231,9 +272,46 @@ pub struct DdpType {
     pub typ: u8,
 }
 
+impl DdpType {
+    pub const RTMP_DATA: Self = DdpType { typ: 1 };
+    pub const NBP: Self = DdpType { typ: 2 };
+    pub const ATP: Self = DdpType { typ: 3 };
+    pub const AEP: Self = DdpType { typ: 4 };
+    pub const RTMP_REQUEST: Self = DdpType { typ: 5 };
+    pub const ZIP: Self = DdpType { typ: 6 };
+    pub const ADSP: Self = DdpType { typ: 7 };
+    pub const SNMP: Self = DdpType { typ: 8 };
+    pub const MACIP: Self = DdpType { typ: 22 };
+
+    /// The protocol's name, if it's one of the standard ones.
+    pub fn name(&self) -> Option<&'static str> {
+        Some(match *self {
+            Self::RTMP_DATA => "RTMP data",
+            Self::NBP => "NBP",
+            Self::ATP => "ATP",
+            Self::AEP => "AEP",
+            Self::RTMP_REQUEST => "RTMP request",
+            Self::ZIP => "ZIP",
+            Self::ADSP => "ADSP",
+            Self::SNMP => "SNMP",
+            Self::MACIP => "MacIP",
+            _ => return None,
+        })
+    }
+}
+
+impl From<u8> for DdpType {
+    fn from(typ: u8) -> Self {
+        DdpType { typ }
+    }
+}
+
 impl fmt::Debug for DdpType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "DdpType<${:02x}>", self.typ)
+        match self.name() {
+            Some(name) => write!(f, "DdpType<${:02x} {}>", self.typ, name),
+            None => write!(f, "DdpType<${:02x}>", self.typ),
+        }
     }
 }
 
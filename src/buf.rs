@@ -0,0 +1,204 @@
+// © 2022 <_@habnab.it>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Packet buffers shared between the layers of the stack. A frame that comes
+//! in is sliced down to its payload rather than copied, and a datagram going
+//! out is allocated with room in front for the headers each layer adds.
+
+use std::{
+    cmp, fmt,
+    hash::{Hash, Hasher},
+    ops::{Bound, Deref, RangeBounds},
+    sync::Arc,
+};
+
+use crate::{ddp::DDP_HEADER_LEN, link::ELAP_HEADER_LEN};
+
+/// Enough room for a long DDP header inside an ELAP frame.
+pub const FRAME_HEADROOM: usize = ELAP_HEADER_LEN + DDP_HEADER_LEN;
+
+/// A reference-counted view of some bytes. Clones and slices share storage;
+/// `push_front` writes into the storage in place when nothing else shares it.
+#[derive(Clone)]
+pub struct PacketBuf {
+    buf: Arc<Vec<u8>>,
+    start: usize,
+    end: usize,
+}
+
+impl PacketBuf {
+    /// A copy of `data`, with `headroom` bytes free in front of it.
+    pub fn with_headroom(headroom: usize, data: &[u8]) -> Self {
+        let mut buf = Vec::with_capacity(headroom + data.len());
+        buf.resize(headroom, 0);
+        buf.extend_from_slice(data);
+        PacketBuf {
+            start: headroom,
+            end: buf.len(),
+            buf: Arc::new(buf),
+        }
+    }
+
+    /// How many bytes `push_front` can add without reallocating.
+    pub fn headroom(&self) -> usize {
+        self.start
+    }
+
+    /// Grow the front by `len` bytes, returning them to be filled in. Copies
+    /// only if the headroom is too small or the storage is shared.
+    pub fn push_front(&mut self, len: usize) -> &mut [u8] {
+        if self.start < len || Arc::get_mut(&mut self.buf).is_none() {
+            *self = PacketBuf::with_headroom(cmp::max(len, FRAME_HEADROOM), self);
+        }
+        self.start -= len;
+        let buf = Arc::get_mut(&mut self.buf).expect("storage was just made unique");
+        &mut buf[self.start..self.start + len]
+    }
+
+    /// Drop `len` bytes off the front, such as a header that's been parsed.
+    pub fn advance(&mut self, len: usize) {
+        assert!(len <= self.len(), "advanced past the end of a PacketBuf");
+        self.start += len;
+    }
+
+    /// Shorten to `len` bytes, keeping the front.
+    pub fn truncate(&mut self, len: usize) {
+        self.end = cmp::min(self.end, self.start + len);
+    }
+
+    /// The bytes in `range`, sharing storage with `self`.
+    pub fn slice(&self, range: impl RangeBounds<usize>) -> Self {
+        let start = match range.start_bound() {
+            Bound::Included(&n) => n,
+            Bound::Excluded(&n) => n + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&n) => n + 1,
+            Bound::Excluded(&n) => n,
+            Bound::Unbounded => self.len(),
+        };
+        assert!(
+            start <= end && end <= self.len(),
+            "slice {}..{} out of a {}b PacketBuf",
+            start,
+            end,
+            self.len()
+        );
+        PacketBuf {
+            buf: self.buf.clone(),
+            start: self.start + start,
+            end: self.start + end,
+        }
+    }
+
+    /// The bytes as a `Vec`, which only copies if they don't already have
+    /// one to themselves.
+    pub fn into_vec(self) -> Vec<u8> {
+        let (start, end) = (self.start, self.end);
+        match Arc::try_unwrap(self.buf) {
+            Ok(mut buf) if start == 0 => {
+                buf.truncate(end);
+                buf
+            }
+            Ok(buf) => buf[start..end].to_vec(),
+            Err(buf) => buf[start..end].to_vec(),
+        }
+    }
+}
+
+impl Default for PacketBuf {
+    fn default() -> Self {
+        Vec::new().into()
+    }
+}
+
+impl Deref for PacketBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[self.start..self.end]
+    }
+}
+
+impl AsRef<[u8]> for PacketBuf {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl From<Vec<u8>> for PacketBuf {
+    fn from(buf: Vec<u8>) -> Self {
+        PacketBuf {
+            start: 0,
+            end: buf.len(),
+            buf: Arc::new(buf),
+        }
+    }
+}
+
+impl From<&[u8]> for PacketBuf {
+    fn from(data: &[u8]) -> Self {
+        data.to_vec().into()
+    }
+}
+
+impl From<PacketBuf> for Vec<u8> {
+    fn from(buf: PacketBuf) -> Self {
+        buf.into_vec()
+    }
+}
+
+impl fmt::Debug for PacketBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl PartialEq for PacketBuf {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for PacketBuf {}
+
+impl PartialOrd for PacketBuf {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PacketBuf {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl Hash for PacketBuf {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl PartialEq<[u8]> for PacketBuf {
+    fn eq(&self, other: &[u8]) -> bool {
+        **self == *other
+    }
+}
+
+impl<const N: usize> PartialEq<[u8; N]> for PacketBuf {
+    fn eq(&self, other: &[u8; N]) -> bool {
+        **self == *other
+    }
+}
+
+impl<T: ?Sized> PartialEq<&T> for PacketBuf
+where
+    PacketBuf: PartialEq<T>,
+{
+    fn eq(&self, other: &&T) -> bool {
+        *self == **other
+    }
+}
@@ -2,14 +2,45 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use futures_sink::Sink;
 use packed_struct::prelude::*;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio_stream::Stream;
+use tokio_util::sync::PollSender;
+
+use crate::{
+    addr::*,
+    buf::{PacketBuf, FRAME_HEADROOM},
+    Result,
+};
 
-use crate::{addr::*, Result};
+pub const DDP_HEADER_LEN: usize = 13;
+pub const DDP_SHORT_HEADER_LEN: usize = 5;
+/// The most a single datagram can carry.
+pub const DDP_MAX_PAYLOAD: usize = 586;
+/// Routers discard datagrams that arrive having already been through this
+/// many, but one can still reach us with that many on its last hop.
+pub const DDP_MAX_HOPS: u8 = 15;
+/// Where the checksummed part of a packed header starts: just past the
+/// checksum itself.
+const DDP_CHECKSUM_START: usize = 4;
 
 pub fn ddp_checksum(bytes: &[u8]) -> u16 {
+    ddp_checksum_iter(bytes.iter().copied())
+}
+
+fn ddp_checksum_iter(bytes: impl Iterator<Item = u8>) -> u16 {
     let mut ret = 0u16;
-    for &b in bytes {
+    for b in bytes {
         ret = ret.wrapping_add(b as u16).rotate_left(1);
     }
     if ret == 0 {
@@ -68,41 +99,284 @@ impl Ddp {
         self.dest_node = addr.node;
     }
 
-    pub fn set_checksum_from(&mut self, buf: &[u8]) {
-        self.checksum = ddp_checksum(buf);
+    /// The checksum for this header followed by `payload`. It covers
+    /// everything after the checksum field, so addresses and the length need
+    /// to be filled in first.
+    pub fn compute_checksum(&self, payload: &[u8]) -> Result<u16> {
+        let packed = self.pack()?;
+        let header = packed[DDP_CHECKSUM_START..].iter();
+        Ok(ddp_checksum_iter(header.chain(payload).copied()))
+    }
+
+    pub fn set_checksum_from(&mut self, payload: &[u8]) -> Result<()> {
+        self.checksum = self.compute_checksum(payload)?;
+        Ok(())
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Why an inbound datagram was dropped before reaching any socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DropReason {
+    /// The header's length disagrees with what arrived.
+    BadLength,
+    /// The two bits ahead of the hop count weren't zero.
+    Reserved,
+    /// A checksum was given and didn't match.
+    BadChecksum,
+    /// Over `DDP_MAX_PAYLOAD`.
+    TooLarge,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 4] = [
+        DropReason::BadLength,
+        DropReason::Reserved,
+        DropReason::BadChecksum,
+        DropReason::TooLarge,
+    ];
+}
+
+/// How many inbound datagrams a port has dropped, by reason.
+#[derive(Debug, Default)]
+pub struct DropCounters([AtomicU64; DropReason::ALL.len()]);
+
+impl DropCounters {
+    pub(crate) fn count(&self, reason: DropReason) {
+        self.0[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, reason: DropReason) -> u64 {
+        self.0[reason as usize].load(Ordering::Relaxed)
+    }
+}
+
+/// Check an inbound datagram, given its header's first byte (whose top two
+/// bits should be zero) and the bytes after its header, returning the
+/// payload with any link padding removed. Any hop count is fine: the field
+/// can't go past the most a router will pass on.
+pub(crate) fn validate<'d>(
+    first: u8,
+    ddp: &Ddp,
+    rest: &'d [u8],
+) -> std::result::Result<&'d [u8], DropReason> {
+    if first & 0xc0 != 0 {
+        return Err(DropReason::Reserved);
+    }
+    let len = (ddp.length as usize)
+        .checked_sub(DDP_HEADER_LEN)
+        .ok_or(DropReason::BadLength)?;
+    if len > DDP_MAX_PAYLOAD {
+        return Err(DropReason::TooLarge);
+    }
+    // ethernet pads short frames, so only the DDP length is authoritative
+    let payload = rest.get(..len).ok_or(DropReason::BadLength)?;
+    if ddp.checksum != 0 && ddp.compute_checksum(payload).ok() != Some(ddp.checksum) {
+        return Err(DropReason::BadChecksum);
+    }
+    Ok(payload)
+}
+
+/// The header LocalTalk uses for datagrams that stay on one network. The
+/// nodes are in the LLAP header, and the network is whichever one the link is.
+#[derive(PackedStruct, Debug, Clone)]
+#[packed_struct(endian = "msb", bit_numbering = "msb0")]
+pub struct DdpShort {
+    #[packed_field(element_size_bits = "6")]
+    pub _reserved: ReservedZero<packed_bits::Bits<6>>,
+    #[packed_field(element_size_bits = "10")]
+    pub length: u16,
+    #[packed_field(element_size_bytes = "1", ty = "enum")]
+    pub dest_socket: AppletalkSocket,
+    #[packed_field(element_size_bytes = "1", ty = "enum")]
+    pub src_socket: AppletalkSocket,
+    #[packed_field(element_size_bytes = "1")]
+    pub typ: DdpType,
+}
+
+impl DdpShort {
+    /// The long form of this header, on network `net` between the given
+    /// LLAP nodes.
+    pub fn to_long(&self, net: u16, dest_node: AppletalkNode, src_node: AppletalkNode) -> Ddp {
+        let extra = (DDP_HEADER_LEN - DDP_SHORT_HEADER_LEN) as u16;
+        Ddp {
+            _reserved: Default::default(),
+            hop_count: 0,
+            length: self.length + extra,
+            checksum: 0,
+            dest_net: net,
+            src_net: net,
+            dest_node,
+            src_node,
+            dest_socket: self.dest_socket,
+            src_socket: self.src_socket,
+            typ: self.typ,
+        }
+    }
+}
+
+impl Ddp {
+    /// The short form of this header, if nothing would be lost: it hasn't
+    /// crossed a router, has no checksum, and either net is 0 or the same as
+    /// `net`. The nodes go in the LLAP header.
+    pub fn to_short(&self, net: u16) -> Option<DdpShort> {
+        let on_net = |n| n == 0 || n == net;
+        if self.hop_count != 0
+            || self.checksum != 0
+            || !on_net(self.dest_net)
+            || !on_net(self.src_net)
+        {
+            return None;
+        }
+        let extra = (DDP_HEADER_LEN - DDP_SHORT_HEADER_LEN) as u16;
+        Some(DdpShort {
+            _reserved: Default::default(),
+            length: self.length.checked_sub(extra)?,
+            dest_socket: self.dest_socket,
+            src_socket: self.src_socket,
+            typ: self.typ,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct DdpHeader {
     pub addr: Appletalk,
     pub socket: AppletalkSocket,
     pub typ: DdpType,
 }
 
+/// A datagram for the stack to send from one of its sockets.
+#[derive(Debug)]
+pub struct DdpOutbound {
+    pub header: Ddp,
+    pub payload: PacketBuf,
+    /// The sending socket opted in to broadcasts.
+    pub broadcast: bool,
+    /// Send to this zone's multicast address rather than the broadcast one.
+    pub zone: Option<Vec<u8>>,
+}
+
+/// A datagram on its way from a `DdpSocket` to the stack, and where to say
+/// how it went.
+#[derive(Debug)]
+pub(crate) struct Outgoing {
+    pub(crate) datagram: DdpOutbound,
+    pub(crate) sent: oneshot::Sender<Result<()>>,
+}
+
+type SentReceiver = oneshot::Receiver<Result<()>>;
+
+/// Buffer for `sendto` and friends to copy a caller's bytes into, leaving
+/// room for the stack to put the headers in front.
+fn outbound_buf(buf: &[u8]) -> PacketBuf {
+    PacketBuf::with_headroom(FRAME_HEADROOM, buf)
+}
+
+/// A datagram received on, or to be sent from, a `DdpSocket`. The header is
+/// the remote end: the source of one received, the destination of one sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DdpDatagram {
+    pub header: DdpHeader,
+    pub payload: PacketBuf,
+}
+
+impl DdpDatagram {
+    fn received((ddp, payload): (Ddp, PacketBuf)) -> Self {
+        DdpDatagram {
+            header: DdpHeader {
+                addr: ddp.source(),
+                socket: ddp.src_socket,
+                typ: ddp.typ,
+            },
+            payload,
+        }
+    }
+}
+
+/// What `recvfrom` copied into the caller's buffer.
+#[derive(Debug, Clone, Copy)]
+pub struct Received {
+    pub len: usize,
+    pub header: DdpHeader,
+    /// The datagram didn't fit, and everything past `len` was discarded.
+    pub truncated: bool,
+}
+
 #[derive(Debug)]
 pub struct DdpSocket {
     pub(crate) addr: Appletalk,
+    pub(crate) addr_rx: watch::Receiver<Option<Appletalk>>,
     pub(crate) socket: AppletalkSocket,
-    pub(crate) ddp_tx: mpsc::Sender<(Ddp, Vec<u8>)>,
-    pub(crate) ddp_rx: mpsc::Receiver<(Ddp, Vec<u8>)>,
+    pub(crate) ddp_tx: PollSender<Outgoing>,
+    pub(crate) ddp_rx: mpsc::Receiver<(Ddp, PacketBuf)>,
+    /// Confirmations for datagrams sent through `Sink`, oldest first.
+    pub(crate) unconfirmed: VecDeque<SentReceiver>,
+    pub(crate) broadcast: AtomicBool,
 }
 
 impl DdpSocket {
+    pub(crate) fn new(
+        addr: Appletalk,
+        addr_rx: watch::Receiver<Option<Appletalk>>,
+        socket: AppletalkSocket,
+        ddp_tx: mpsc::Sender<Outgoing>,
+        ddp_rx: mpsc::Receiver<(Ddp, PacketBuf)>,
+    ) -> Self {
+        DdpSocket {
+            addr,
+            addr_rx,
+            socket,
+            ddp_tx: PollSender::new(ddp_tx),
+            ddp_rx,
+            unconfirmed: VecDeque::new(),
+            broadcast: AtomicBool::new(false),
+        }
+    }
+
+    /// The stack's current address, or the one this socket was bound with
+    /// while the stack is re-acquiring after a conflict.
     pub fn local_addr(&self) -> Appletalk {
-        self.addr
+        self.addr_rx.borrow().unwrap_or(self.addr)
+    }
+
+    /// Wait for the stack's address to change. `None` means it was lost to
+    /// another node and a new one is being acquired; datagrams sent until
+    /// then are dropped.
+    pub async fn address_changed(&mut self) -> Result<Option<Appletalk>> {
+        self.addr_rx
+            .changed()
+            .await
+            .map_err(|_| crate::CrabbletalkError::Hangup)?;
+        let addr = *self.addr_rx.borrow();
+        if let Some(addr) = addr {
+            self.addr = addr;
+        }
+        Ok(addr)
     }
 
     pub fn local_socket(&self) -> AppletalkSocket {
         self.socket
     }
 
-    pub async fn sendto(&self, buf: &[u8], dest: DdpHeader) -> Result<()> {
+    /// Allow sending to `AppletalkNode::Broadcast` and zone multicasts, which
+    /// otherwise fail with `BroadcastNotEnabled`.
+    pub fn set_broadcast(&self, on: bool) {
+        self.broadcast.store(on, Ordering::Relaxed);
+    }
+
+    pub fn broadcast(&self) -> bool {
+        self.broadcast.load(Ordering::Relaxed)
+    }
+
+    fn outbound(&self, buf: PacketBuf, dest: DdpHeader) -> Result<(Outgoing, SentReceiver)> {
+        if buf.len() > DDP_MAX_PAYLOAD {
+            return Err(crate::CrabbletalkError::PayloadTooLarge(buf.len()));
+        }
         let header = Ddp {
             _reserved: Default::default(),
             hop_count: 0,
             length: 0,
-            checksum: ddp_checksum(buf),
+            checksum: 0, // filled in by the stack
             dest_net: dest.addr.net,
             src_net: 0,
             dest_node: dest.addr.node,
@@ -111,26 +385,287 @@ impl DdpSocket {
             src_socket: self.socket,
             typ: dest.typ,
         };
-        self.ddp_tx
-            .send((header, buf.to_owned()))
+        let (sent, sent_rx) = oneshot::channel();
+        let datagram = DdpOutbound {
+            header,
+            payload: buf,
+            broadcast: self.broadcast(),
+            zone: None,
+        };
+        let out = Outgoing { datagram, sent };
+        Ok((out, sent_rx))
+    }
+
+    fn sender(&self) -> Result<&mpsc::Sender<Outgoing>> {
+        self.ddp_tx.get_ref().ok_or(crate::CrabbletalkError::Hangup)
+    }
+
+    /// Send a datagram, waiting until the stack has put it on the wire or
+    /// given up on it.
+    pub async fn sendto(&self, buf: &[u8], dest: DdpHeader) -> Result<()> {
+        self.sendto_buf(outbound_buf(buf), dest).await
+    }
+
+    /// `sendto` for a payload that's already in a `PacketBuf`, such as one
+    /// just received, which goes out without being copied if nothing else
+    /// holds on to it.
+    pub async fn sendto_buf(&self, buf: PacketBuf, dest: DdpHeader) -> Result<()> {
+        let (out, sent_rx) = self.outbound(buf, dest)?;
+        self.sender()?
+            .send(out)
             .await
             .map_err(|_| crate::CrabbletalkError::Hangup)?;
-        Ok(())
+        sent_rx.await.map_err(|_| crate::CrabbletalkError::Hangup)?
+    }
+
+    /// Send a datagram to every node in `zone` on our cable, via the zone's
+    /// multicast address. Needs `set_broadcast`.
+    pub async fn sendto_zone(
+        &self,
+        buf: &[u8],
+        zone: &[u8],
+        socket: AppletalkSocket,
+        typ: DdpType,
+    ) -> Result<()> {
+        let dest = DdpHeader {
+            addr: Appletalk {
+                net: 0,
+                node: AppletalkNode::Broadcast,
+            },
+            socket,
+            typ,
+        };
+        let (mut out, sent_rx) = self.outbound(outbound_buf(buf), dest)?;
+        out.datagram.zone = Some(zone.to_owned());
+        self.sender()?
+            .send(out)
+            .await
+            .map_err(|_| crate::CrabbletalkError::Hangup)?;
+        sent_rx.await.map_err(|_| crate::CrabbletalkError::Hangup)?
+    }
+
+    /// Queue a datagram without waiting, failing with `Transient` if the
+    /// socket's queue is full. Whether it then makes it out isn't reported.
+    pub fn try_send(&self, buf: &[u8], dest: DdpHeader) -> Result<()> {
+        let (out, _) = self.outbound(outbound_buf(buf), dest)?;
+        self.sender()?.try_send(out).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => crate::CrabbletalkError::Transient,
+            mpsc::error::TrySendError::Closed(_) => crate::CrabbletalkError::Hangup,
+        })
+    }
+
+    /// Copy the next datagram into `buf_out`, discarding whatever doesn't fit.
+    pub async fn recvfrom(&mut self, buf_out: &mut [u8]) -> Result<Received> {
+        let datagram = self.recv().await?;
+        let len = datagram.payload.len().min(buf_out.len());
+        buf_out[..len].copy_from_slice(&datagram.payload[..len]);
+        Ok(Received {
+            len,
+            header: datagram.header,
+            truncated: len < datagram.payload.len(),
+        })
     }
 
-    pub async fn recvfrom(&mut self, buf_out: &mut [u8]) -> Result<(usize, DdpHeader)> {
-        let (ddp, buf_in) = self
-            .ddp_rx
+    pub async fn recv(&mut self) -> Result<DdpDatagram> {
+        self.ddp_rx
             .recv()
             .await
-            .ok_or(crate::CrabbletalkError::Hangup)?;
-        let len = buf_in.len().min(buf_out.len());
-        (&mut buf_out[..len]).copy_from_slice(&buf_in[..len]);
-        let header = DdpHeader {
-            addr: ddp.source(),
-            socket: ddp.src_socket,
-            typ: ddp.typ,
+            .map(DdpDatagram::received)
+            .ok_or(crate::CrabbletalkError::Hangup)
+    }
+
+    /// `None` if nothing arrived within `timeout`.
+    pub async fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<DdpDatagram>> {
+        match tokio::time::timeout(timeout, self.recv()).await {
+            Ok(res) => res.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// `None` if nothing is waiting.
+    pub fn try_recv(&mut self) -> Result<Option<DdpDatagram>> {
+        match self.ddp_rx.try_recv() {
+            Ok(received) => Ok(Some(DdpDatagram::received(received))),
+            Err(mpsc::error::TryRecvError::Empty) => Ok(None),
+            Err(mpsc::error::TryRecvError::Disconnected) => Err(crate::CrabbletalkError::Hangup),
+        }
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<DdpDatagram>> {
+        self.ddp_rx.poll_recv(cx).map(|received| {
+            received
+                .map(DdpDatagram::received)
+                .ok_or(crate::CrabbletalkError::Hangup)
+        })
+    }
+
+    /// Ready once there's room to send another datagram with `Sink`, or with
+    /// the error from an earlier one that didn't make it out.
+    pub fn poll_send_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.take_confirmed()?;
+        self.ddp_tx
+            .poll_reserve(cx)
+            .map_err(|_| crate::CrabbletalkError::Hangup)
+    }
+
+    /// Ready once every datagram sent with `Sink` so far has gone out or
+    /// failed, with the first failure.
+    pub fn poll_send_flushed(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        while let Some(sent_rx) = self.unconfirmed.front_mut() {
+            let res = ready!(Pin::new(sent_rx).poll(cx));
+            self.unconfirmed.pop_front();
+            res.map_err(|_| crate::CrabbletalkError::Hangup)??;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Drop confirmations which have already arrived, returning the first
+    /// failure among them.
+    fn take_confirmed(&mut self) -> Result<()> {
+        while let Some(sent_rx) = self.unconfirmed.front_mut() {
+            match sent_rx.try_recv() {
+                Ok(res) => {
+                    self.unconfirmed.pop_front();
+                    res?;
+                }
+                Err(oneshot::error::TryRecvError::Empty) => break,
+                Err(oneshot::error::TryRecvError::Closed) => {
+                    self.unconfirmed.pop_front();
+                    return Err(crate::CrabbletalkError::Hangup);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Stream for DdpSocket {
+    type Item = DdpDatagram;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_recv(cx).map(Result::ok)
+    }
+}
+
+impl Sink<DdpDatagram> for DdpSocket {
+    type Error = crate::CrabbletalkError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_send_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: DdpDatagram) -> Result<()> {
+        let this = self.get_mut();
+        let (out, sent_rx) = this.outbound(item.payload, item.header)?;
+        this.ddp_tx
+            .send_item(out)
+            .map_err(|_| crate::CrabbletalkError::Hangup)?;
+        this.unconfirmed.push_back(sent_rx);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_send_flushed(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        let res = ready!(this.poll_send_flushed(cx));
+        this.ddp_tx.close();
+        Poll::Ready(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagram(payload: &[u8]) -> Ddp {
+        let mut ddp = Ddp {
+            _reserved: Default::default(),
+            hop_count: 2,
+            length: (DDP_HEADER_LEN + payload.len()) as u16,
+            checksum: 0,
+            dest_net: 1,
+            src_net: 2,
+            dest_node: AppletalkNode::Node(3),
+            src_node: AppletalkNode::Node(4),
+            dest_socket: AppletalkSocket::ECHO,
+            src_socket: AppletalkSocket::Dynamic(200),
+            typ: DdpType::AEP,
         };
-        Ok((len, header))
+        ddp.set_checksum_from(payload).unwrap();
+        ddp
+    }
+
+    fn first(ddp: &Ddp) -> u8 {
+        ddp.pack().unwrap()[0]
+    }
+
+    #[test]
+    fn valid_with_padding() {
+        let ddp = datagram(b"hello");
+        assert_eq!(
+            validate(first(&ddp), &ddp, b"hello\0\0\0"),
+            Ok(&b"hello"[..])
+        );
+    }
+
+    #[test]
+    fn bad_length() {
+        let mut ddp = datagram(b"hello");
+        assert_eq!(
+            validate(first(&ddp), &ddp, b"hell"),
+            Err(DropReason::BadLength)
+        );
+        ddp.length = DDP_HEADER_LEN as u16 - 1;
+        assert_eq!(
+            validate(first(&ddp), &ddp, b"hello"),
+            Err(DropReason::BadLength)
+        );
+    }
+
+    #[test]
+    fn too_large() {
+        let payload = [0; DDP_MAX_PAYLOAD + 1];
+        let mut ddp = datagram(&[]);
+        ddp.length = (DDP_HEADER_LEN + payload.len()) as u16;
+        assert_eq!(
+            validate(first(&ddp), &ddp, &payload),
+            Err(DropReason::TooLarge)
+        );
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let ddp = datagram(b"hello");
+        assert_eq!(
+            validate(first(&ddp), &ddp, b"jello"),
+            Err(DropReason::BadChecksum)
+        );
+        let mut unchecked = datagram(b"hello");
+        unchecked.checksum = 0;
+        assert_eq!(
+            validate(first(&unchecked), &unchecked, b"jello"),
+            Ok(&b"jello"[..])
+        );
+    }
+
+    #[test]
+    fn last_hop_is_delivered() {
+        let mut ddp = datagram(b"hello");
+        ddp.hop_count = DDP_MAX_HOPS;
+        assert_eq!(validate(first(&ddp), &ddp, b"hello"), Ok(&b"hello"[..]));
+    }
+
+    #[test]
+    fn reserved_bits() {
+        let ddp = datagram(b"hello");
+        for bits in [0x40, 0x80] {
+            assert_eq!(
+                validate(first(&ddp) | bits, &ddp, b"hello"),
+                Err(DropReason::Reserved)
+            );
+        }
     }
 }
@@ -6,11 +6,17 @@ use packed_struct::{types::bits::ByteArray, PackedStruct, PackedStructSlice, Pac
 
 pub mod aarp;
 pub mod addr;
+pub mod buf;
 pub mod ddp;
 pub mod link;
+pub mod rtmp;
+pub mod stats;
+pub mod zip;
 
 use thiserror::Error;
 
+use crate::addr::Appletalk;
+
 #[derive(Error, Debug)]
 pub enum CrabbletalkError {
     #[error("packed_struct error")]
@@ -19,12 +25,57 @@ pub enum CrabbletalkError {
     Hangup,
     #[error("transient")]
     Transient,
+    #[error("no AARP response for {0:?}")]
+    ResolutionTimeout(Appletalk),
+    #[error("too many datagrams already waiting on AARP for {0:?}")]
+    PendingQueueFull(Appletalk),
+    #[error("gave up acquiring an address after {0} conflicts")]
+    AddressConflicts(usize),
+    #[error("invalid stack config: {0}")]
+    InvalidConfig(&'static str),
+    #[error("a stack needs at least one port")]
+    NoPorts,
+    #[error("no port {0:?} on this stack")]
+    UnknownPort(crate::aarp::PortId),
+    #[error("socket {0:?} is already bound")]
+    SocketInUse(crate::addr::AppletalkSocket),
+    #[error("every dynamic socket is bound")]
+    SocketsExhausted,
+    #[error("broadcasting wasn't enabled on this socket")]
+    BroadcastNotEnabled,
+    #[error("{0}b is more than a datagram can carry")]
+    PayloadTooLarge(usize),
+    #[error("no address acquired yet")]
+    NotAcquired,
+    #[error("no route to {0:?}")]
+    NoRoute(Appletalk),
+    #[error("malformed packet: {0:?}")]
+    Malformed(MalformedReason),
+}
+
+/// What was wrong with a packet that couldn't be parsed or was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MalformedReason {
+    /// Shorter than its headers need it to be.
+    Truncated,
+    /// A field held a value it can't.
+    InvalidField,
+    /// An LLAP frame which doesn't carry DDP.
+    NotDdp,
+    /// A DDP datagram which failed ingress validation.
+    Ddp(crate::ddp::DropReason),
+}
+
+impl From<MalformedReason> for CrabbletalkError {
+    fn from(reason: MalformedReason) -> Self {
+        CrabbletalkError::Malformed(reason)
+    }
 }
 
 pub type Result<T> = std::result::Result<T, CrabbletalkError>;
 
 pub trait UnpackSplit {
-    fn unpack_split<'d>(data: &'d [u8]) -> Result<(Self, &'d [u8])>
+    fn unpack_split(data: &[u8]) -> Result<(Self, &[u8])>
     where
         Self: Sized;
 }
@@ -34,12 +85,16 @@ where
     T: PackedStruct,
     T::ByteArray: ByteArray,
 {
-    fn unpack_split<'d>(data: &'d [u8]) -> Result<(Self, &'d [u8])>
+    fn unpack_split(data: &[u8]) -> Result<(Self, &[u8])>
     where
         Self: Sized,
     {
-        let (lhs, rhs) = data.split_at(<T as PackedStruct>::ByteArray::len());
-        let lhs = T::unpack_from_slice(lhs)?;
+        let len = <T as PackedStruct>::ByteArray::len();
+        if data.len() < len {
+            return Err(MalformedReason::Truncated.into());
+        }
+        let (lhs, rhs) = data.split_at(len);
+        let lhs = T::unpack_from_slice(lhs).map_err(|_| MalformedReason::InvalidField)?;
         Ok((lhs, rhs))
     }
 }
@@ -6,11 +6,16 @@ use std::fmt;
 
 use packed_struct::prelude::*;
 
-use crate::addr::*;
+use crate::{
+    addr::*,
+    buf::PacketBuf,
+    ddp::{Ddp, DdpShort},
+    MalformedReason, Result, UnpackSplit,
+};
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(transparent)]
-pub struct AppletalkPacket(pub Vec<u8>);
+pub struct AppletalkPacket(pub PacketBuf);
 
 impl fmt::Debug for AppletalkPacket {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -18,6 +23,9 @@ impl fmt::Debug for AppletalkPacket {
     }
 }
 
+/// Ethernet plus the 802.2 SNAP header.
+pub const ELAP_HEADER_LEN: usize = 22;
+
 #[derive(PackedStruct, Debug, Clone)]
 #[packed_struct(endian = "msb", bit_numbering = "msb0")]
 pub struct Elap {
@@ -39,3 +47,63 @@ pub struct Elap {
     #[packed_field(element_size_bytes = "2")]
     pub ethertype: Ethertype,
 }
+
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LlapType {
+    DdpShort = 0x01,
+    DdpLong = 0x02,
+    Enq = 0x81,
+    Ack = 0x82,
+    Rts = 0x84,
+    Cts = 0x85,
+}
+
+#[derive(PackedStruct, Debug, Clone)]
+#[packed_struct(endian = "msb", bit_numbering = "msb0")]
+pub struct Llap {
+    #[packed_field(element_size_bytes = "1", ty = "enum")]
+    pub dest_node: AppletalkNode,
+    #[packed_field(element_size_bytes = "1", ty = "enum")]
+    pub src_node: AppletalkNode,
+    #[packed_field(element_size_bytes = "1", ty = "enum")]
+    pub typ: LlapType,
+}
+
+/// Unpack a DDP datagram from an LLAP frame in either header form, giving
+/// back the long form. Short headers are taken to be on network `net`.
+pub fn unpack_llap_ddp(data: &[u8], net: u16) -> Result<(Llap, Ddp, &[u8])> {
+    let (llap, data) = Llap::unpack_split(data)?;
+    match llap.typ {
+        LlapType::DdpShort => {
+            let (short, payload) = DdpShort::unpack_split(data)?;
+            let ddp = short.to_long(net, llap.dest_node, llap.src_node);
+            Ok((llap, ddp, payload))
+        }
+        LlapType::DdpLong => {
+            let (ddp, payload) = Ddp::unpack_split(data)?;
+            Ok((llap, ddp, payload))
+        }
+        _ => Err(MalformedReason::NotDdp.into()),
+    }
+}
+
+/// Pack a DDP datagram into an LLAP frame, using the short header if
+/// `prefer_short` and the datagram stays on network `net`.
+pub fn pack_llap_ddp(ddp: &Ddp, payload: &[u8], net: u16, prefer_short: bool) -> Result<Vec<u8>> {
+    let short = ddp.to_short(net).filter(|_| prefer_short);
+    let llap = Llap {
+        dest_node: ddp.dest_node,
+        src_node: ddp.src_node,
+        typ: match short {
+            Some(_) => LlapType::DdpShort,
+            None => LlapType::DdpLong,
+        },
+    };
+    let mut ret = llap.pack_to_vec()?;
+    match short {
+        Some(short) => ret.extend_from_slice(&short.pack()?),
+        None => ret.extend_from_slice(&ddp.pack()?),
+    }
+    ret.extend_from_slice(payload);
+    Ok(ret)
+}
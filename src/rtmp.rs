@@ -0,0 +1,77 @@
+// © 2022 <_@habnab.it>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use std::ops::RangeInclusive;
+
+use packed_struct::prelude::*;
+
+use crate::{addr::*, MalformedReason, Result, UnpackSplit};
+
+pub const RTMP_VERSION: u8 = 0x82;
+
+#[derive(PackedStruct, Debug, Clone)]
+#[packed_struct(endian = "msb", bit_numbering = "msb0")]
+pub struct RtmpDataHeader {
+    pub router_net: u16,
+    pub id_length: u8,
+    #[packed_field(element_size_bytes = "1", ty = "enum")]
+    pub router_node: AppletalkNode,
+}
+
+impl RtmpDataHeader {
+    pub fn router(&self) -> Appletalk {
+        Appletalk {
+            net: self.router_net,
+            node: self.router_node,
+        }
+    }
+}
+
+#[derive(PackedStruct, Debug, Clone)]
+#[packed_struct(endian = "msb", bit_numbering = "msb0")]
+pub struct RtmpTuple {
+    pub net: u16,
+    #[packed_field(element_size_bits = "1")]
+    pub extended: bool,
+    #[packed_field(element_size_bits = "7")]
+    pub distance: u8,
+}
+
+#[derive(PackedStruct, Debug, Clone)]
+#[packed_struct(endian = "msb", bit_numbering = "msb0")]
+pub struct RtmpExtendedTail {
+    pub range_end: u16,
+    pub version: u8,
+}
+
+/// What a node needs from an RTMP data packet: who sent it, and the network
+/// range of the cable it was sent on. The routing tuples after that are only
+/// interesting to other routers.
+#[derive(Debug, Clone)]
+pub struct RtmpData {
+    pub router: Appletalk,
+    pub cable_range: RangeInclusive<u16>,
+}
+
+impl RtmpData {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let (header, data) = RtmpDataHeader::unpack_split(data)?;
+        let cable_range = if data.get(..3) == Some(&[0, 0, RTMP_VERSION]) {
+            // a non-extended network's version triplet stands in for the
+            // first tuple, and its only number is the router's own
+            header.router_net..=header.router_net
+        } else {
+            let (first, data) = RtmpTuple::unpack_split(data)?;
+            let (tail, _) = RtmpExtendedTail::unpack_split(data)?;
+            if !first.extended || tail.version != RTMP_VERSION {
+                return Err(MalformedReason::InvalidField.into());
+            }
+            first.net..=tail.range_end
+        };
+        Ok(RtmpData {
+            router: header.router(),
+            cable_range,
+        })
+    }
+}
@@ -0,0 +1,108 @@
+// © 2022 <_@habnab.it>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Counters and a point-in-time view of a running stack, roughly what
+//! /proc/net/atalk and friends show for the kernel's.
+
+use crate::{
+    aarp::{AarpFunction, NetworkInfo, PortId},
+    addr::{Appletalk, AppletalkSocket, Mac},
+    ddp::DropReason,
+};
+
+/// Ethernet frames, by ethertype.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FrameCounts {
+    pub aarp: u64,
+    pub appletalk: u64,
+    /// Frames that weren't 802.2 SNAP, or were some other protocol.
+    pub other: u64,
+}
+
+/// AARP packets, by function.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AarpCounts {
+    pub requests: u64,
+    pub responses: u64,
+    pub probes: u64,
+}
+
+impl AarpCounts {
+    pub(crate) fn count(&mut self, function: AarpFunction) {
+        match function {
+            AarpFunction::Request => self.requests += 1,
+            AarpFunction::Response => self.responses += 1,
+            AarpFunction::Probe => self.probes += 1,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PortStats {
+    pub frames_in: FrameCounts,
+    pub frames_out: FrameCounts,
+    pub aarp_in: AarpCounts,
+    pub aarp_out: AarpCounts,
+    /// Datagrams addressed to us that passed validation.
+    pub ddp_in: u64,
+    pub ddp_out: u64,
+    /// Datagrams that failed validation, by reason.
+    pub ddp_dropped: Vec<(DropReason, u64)>,
+}
+
+/// Datagrams that got past a port, and what became of them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DeliveryStats {
+    pub delivered: u64,
+    /// Nothing was bound to the destination socket.
+    pub no_socket: u64,
+    /// The socket's queue was full.
+    pub socket_full: u64,
+    /// Sent by one of our sockets to another, without touching a link.
+    pub loopback: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SocketStats {
+    pub datagrams_in: u64,
+    pub bytes_in: u64,
+    pub datagrams_out: u64,
+    pub bytes_out: u64,
+    /// Inbound datagrams thrown away because the socket's queue was full.
+    pub dropped: u64,
+}
+
+/// Where a port is in acquiring an address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhaseSummary {
+    Probing(Appletalk),
+    Accepted(Appletalk),
+    Failed { conflicts: usize },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortSnapshot {
+    pub port: PortId,
+    pub hw: Mac,
+    pub phase: PhaseSummary,
+    pub network: Option<NetworkInfo>,
+    pub amt_entries: usize,
+    /// Addresses we're waiting on AARP responses for.
+    pub pending_resolutions: usize,
+    pub stats: PortStats,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketSnapshot {
+    pub socket: AppletalkSocket,
+    pub port: PortId,
+    pub stats: SocketStats,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackSnapshot {
+    pub ports: Vec<PortSnapshot>,
+    pub sockets: Vec<SocketSnapshot>,
+    pub delivery: DeliveryStats,
+}
@@ -0,0 +1,124 @@
+// © 2022 <_@habnab.it>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use std::ops::RangeInclusive;
+
+use packed_struct::prelude::*;
+
+use crate::{addr::*, ddp::ddp_checksum, MalformedReason, Result, UnpackSplit};
+
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ZipFunction {
+    Query = 1,
+    Reply = 2,
+    GetNetInfo = 5,
+    GetNetInfoReply = 6,
+    Notify = 7,
+    ExtendedReply = 8,
+}
+
+#[derive(PackedStruct, Debug, Clone)]
+#[packed_struct(endian = "msb", bit_numbering = "msb0")]
+pub struct NetInfoHeader {
+    #[packed_field(element_size_bytes = "1", ty = "enum")]
+    pub function: ZipFunction,
+    #[packed_field(element_size_bits = "1")]
+    pub zone_invalid: bool,
+    #[packed_field(element_size_bits = "1")]
+    pub use_broadcast: bool,
+    #[packed_field(element_size_bits = "1")]
+    pub only_one_zone: bool,
+    #[packed_field(element_size_bits = "5")]
+    pub _reserved: ReservedZero<packed_bits::Bits<5>>,
+    pub range_start: u16,
+    pub range_end: u16,
+}
+
+/// Split a length-prefixed string (as zone names are sent) off the front of
+/// `data`.
+fn split_pstring(data: &[u8]) -> Result<(&[u8], &[u8])> {
+    let (&len, data) = data.split_first().ok_or(MalformedReason::Truncated)?;
+    let len = len as usize;
+    if data.len() < len {
+        return Err(MalformedReason::Truncated.into());
+    }
+    Ok(data.split_at(len))
+}
+
+fn push_pstring(out: &mut Vec<u8>, s: &[u8]) {
+    let s = &s[..s.len().min(u8::MAX as usize)];
+    out.push(s.len() as u8);
+    out.extend_from_slice(s);
+}
+
+/// The multicast address for everyone in `zone`: the DDP checksum of the
+/// upper-cased name, modulo 253, in the AppleTalk multicast block. Only ASCII
+/// is upper-cased, which covers every zone name we've seen in practice.
+pub fn zone_multicast(zone: &[u8]) -> Mac {
+    let upper = zone.to_ascii_uppercase();
+    Mac {
+        oui: APPLETALK_OUI,
+        nic: [0, 0, (ddp_checksum(&upper) % 253) as u8],
+    }
+}
+
+/// Ask the routers on a cable which network range and zone it has. An empty
+/// zone asks for the default zone.
+pub fn pack_get_net_info(zone: &[u8]) -> Result<Vec<u8>> {
+    let header = NetInfoHeader {
+        function: ZipFunction::GetNetInfo,
+        zone_invalid: false,
+        use_broadcast: false,
+        only_one_zone: false,
+        _reserved: Default::default(),
+        range_start: 0,
+        range_end: 0,
+    };
+    let mut ret = header.pack_to_vec()?;
+    push_pstring(&mut ret, zone);
+    Ok(ret)
+}
+
+#[derive(Debug, Clone)]
+pub struct NetInfoReply {
+    pub header: NetInfoHeader,
+    pub zone: Vec<u8>,
+    pub multicast: Option<Mac>,
+    pub default_zone: Option<Vec<u8>>,
+}
+
+impl NetInfoReply {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let (header, data) = NetInfoHeader::unpack_split(data)?;
+        let (zone, data) = split_pstring(data)?;
+        let (multicast, data) = split_pstring(data)?;
+        let multicast = match multicast.len() {
+            6 => {
+                Some(Mac::unpack_from_slice(multicast).map_err(|_| MalformedReason::InvalidField)?)
+            }
+            _ => None,
+        };
+        let default_zone = if header.zone_invalid {
+            Some(split_pstring(data)?.0.to_owned())
+        } else {
+            None
+        };
+        Ok(NetInfoReply {
+            header,
+            zone: zone.to_owned(),
+            multicast,
+            default_zone,
+        })
+    }
+
+    pub fn cable_range(&self) -> RangeInclusive<u16> {
+        self.header.range_start..=self.header.range_end
+    }
+
+    /// The zone this cable puts us in: the one we asked for, unless the
+    /// router said it was invalid and gave us the default instead.
+    pub fn effective_zone(&self) -> &[u8] {
+        self.default_zone.as_deref().unwrap_or(&self.zone)
+    }
+}
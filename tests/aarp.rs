@@ -0,0 +1,218 @@
+// © 2022 <_@habnab.it>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+mod support;
+
+use std::time::Duration;
+
+use crabbletalk::{
+    aarp::{AarpStackHandle, ProbeSchedule, StackEventKind},
+    addr::{Appletalk, AppletalkNode},
+    CrabbletalkError,
+};
+use support::{config, hinted, next_event, to, Conditions, Segment, HINT, SOCKET};
+
+#[test]
+#[allow(clippy::reversed_empty_ranges)]
+fn unusable_ranges_are_rejected() {
+    assert!(config(1).validate().is_ok());
+    for bad in [
+        config(1).node_range(5..=4),
+        config(1).node_range(0..=10),
+        config(1).node_range(0x80..=0xFF),
+        config(1).net_range(0xFF10..=0xFF0F),
+        config(1).net_range(0..=10),
+        config(1).net_range(0xFF00..=0xFFFF),
+        config(1).socket_queue_depth(0),
+    ] {
+        assert!(matches!(
+            bad.validate(),
+            Err(CrabbletalkError::InvalidConfig(_))
+        ));
+    }
+}
+
+#[tokio::test]
+async fn zero_queue_depths_are_rejected_before_spawning() {
+    for bad in [
+        config(1).link_queue_depth(0),
+        config(1).input_queue_depth(0),
+        config(1).control_queue_depth(0),
+        config(1).socket_queue_depth(0),
+        config(1).pending_queue_depth(0),
+        config(1).event_queue_depth(0),
+    ] {
+        assert!(matches!(
+            AarpStackHandle::spawn(bad),
+            Err(CrabbletalkError::InvalidConfig(_))
+        ));
+    }
+}
+
+#[test]
+fn a_stack_needs_ports() {
+    assert!(matches!(
+        AarpStackHandle::spawn_ports([]),
+        Err(CrabbletalkError::NoPorts)
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn defended_address_isnt_taken() {
+    let segment = Segment::new(1);
+    let a = segment.join(hinted(1));
+    a.open_ddp_dynamic().await.unwrap();
+    assert_eq!(a.address(), Some(HINT));
+
+    let b = segment.join(hinted(2));
+    let mut events = b.events();
+    let conflict = next_event(&mut events, |k| {
+        matches!(k, StackEventKind::Conflict { .. })
+    })
+    .await;
+    assert_eq!(conflict, StackEventKind::Conflict {
+        addr: HINT,
+        from: hinted(1).hw(),
+    });
+    let got = next_event(&mut events, |k| {
+        matches!(k, StackEventKind::AddressAcquired(_))
+    })
+    .await;
+    assert_ne!(got, StackEventKind::AddressAcquired(HINT));
+    assert_eq!(a.address(), Some(HINT));
+}
+
+#[tokio::test(start_paused = true)]
+async fn simultaneous_probes_end_up_apart() {
+    let segment = Segment::new(2);
+    let a = segment.join(hinted(1));
+    let b = segment.join(hinted(2));
+    let (mut a_events, mut b_events) = (a.events(), b.events());
+    let conflict =
+        |k: &StackEventKind| matches!(k, StackEventKind::Conflict { addr, .. } if *addr == HINT);
+    // whichever hears the other's probe first moves on, and the other may
+    // never hear a probe for HINT again
+    tokio::select! {
+        _ = next_event(&mut a_events, conflict) => (),
+        _ = next_event(&mut b_events, conflict) => (),
+    }
+    a.open_ddp_dynamic().await.unwrap();
+    b.open_ddp_dynamic().await.unwrap();
+    assert_ne!(a.address(), b.address());
+}
+
+#[tokio::test(start_paused = true)]
+async fn gives_up_after_max_conflicts() {
+    let segment = Segment::new(3);
+    let a = segment.join(hinted(1));
+    a.open_ddp_dynamic().await.unwrap();
+
+    let schedule = ProbeSchedule {
+        max_conflicts: 3,
+        ..Default::default()
+    };
+    let b = segment.join(
+        hinted(2)
+            .net_range(HINT.net..=HINT.net)
+            .node_range(42..=42)
+            .probe_schedule(schedule),
+    );
+    let mut events = b.events();
+    let failed = next_event(&mut events, |k| {
+        matches!(k, StackEventKind::AcquisitionFailed { .. })
+    })
+    .await;
+    assert_eq!(failed, StackEventKind::AcquisitionFailed { conflicts: 3 });
+    assert!(matches!(
+        b.open_ddp_dynamic().await,
+        Err(CrabbletalkError::AddressConflicts(3))
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn duplicate_found_after_partition_heals() {
+    let segment = Segment::new(4);
+    let a = segment.join(hinted(1));
+    let b = segment.join(hinted(2));
+    segment.partition(&[&[0], &[1]]);
+    let sa = a.open_ddp_dynamic().await.unwrap();
+    b.open_ddp_dynamic().await.unwrap();
+    assert_eq!(a.address(), Some(HINT));
+    assert_eq!(b.address(), Some(HINT));
+
+    segment.heal();
+    let mut events = b.events();
+    // asking after anyone makes `a` broadcast a request from its address
+    let nobody = Appletalk {
+        net: HINT.net,
+        node: AppletalkNode::Node(7),
+    };
+    let _ = sa.sendto(b"anyone?", to(nobody)).await;
+    let lost = next_event(&mut events, |k| {
+        matches!(k, StackEventKind::AddressLost { .. })
+    })
+    .await;
+    assert_eq!(lost, StackEventKind::AddressLost {
+        addr: HINT,
+        claimed_by: hinted(1).hw(),
+    });
+    next_event(&mut events, |k| {
+        matches!(k, StackEventKind::AddressAcquired(_))
+    })
+    .await;
+    assert_ne!(b.address(), Some(HINT));
+    assert_eq!(a.address(), Some(HINT));
+}
+
+#[tokio::test(start_paused = true)]
+async fn resolution_is_cached() {
+    let segment = Segment::new(5);
+    let a = segment.join_random();
+    let b = segment.join_random();
+    let sa = a.open_ddp_dynamic().await.unwrap();
+    let mut sb = b.open_ddp(SOCKET).await.unwrap();
+    let b_addr = b.address().unwrap();
+    let mut events = a.events();
+
+    sa.sendto(b"one", to(b_addr)).await.unwrap();
+    let learned = next_event(
+        &mut events,
+        |k| matches!(k, StackEventKind::AmtLearned { atalk, .. } if *atalk == b_addr),
+    )
+    .await;
+    let b_hw = b.snapshot().await.unwrap().ports[0].hw;
+    assert_eq!(learned, StackEventKind::AmtLearned {
+        atalk: b_addr,
+        hw: b_hw,
+    });
+    sa.sendto(b"two", to(b_addr)).await.unwrap();
+    assert_eq!(sb.recv().await.unwrap().payload, b"one");
+    assert_eq!(sb.recv().await.unwrap().payload, b"two");
+
+    let stats = &a.snapshot().await.unwrap().ports[0].stats;
+    assert_eq!(stats.aarp_out.requests, 1);
+    assert_eq!(stats.aarp_in.responses, 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn resolution_times_out() {
+    let segment = Segment::new(7);
+    let attempts = 4;
+    let a = segment.join(config(1).aarp_requests(Duration::from_millis(100), attempts));
+    let b = segment.join_random();
+    let sa = a.open_ddp_dynamic().await.unwrap();
+    b.open_ddp_dynamic().await.unwrap();
+    let b_addr = b.address().unwrap();
+
+    segment.set_conditions(Conditions {
+        loss: 1.0,
+        ..Default::default()
+    });
+    assert!(matches!(
+        sa.sendto(b"hello?", to(b_addr)).await,
+        Err(CrabbletalkError::ResolutionTimeout(addr)) if addr == b_addr
+    ));
+    let stats = &a.snapshot().await.unwrap().ports[0].stats;
+    assert_eq!(stats.aarp_out.requests, attempts as u64);
+}
@@ -0,0 +1,43 @@
+// © 2022 <_@habnab.it>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use crabbletalk::buf::{PacketBuf, FRAME_HEADROOM};
+
+#[test]
+fn push_front_fills_headroom_in_place() {
+    let mut buf = PacketBuf::with_headroom(FRAME_HEADROOM, b"payload");
+    let payload_at = buf.as_ptr();
+    buf.push_front(3).copy_from_slice(b"hdr");
+    assert_eq!(buf, b"hdrpayload");
+    assert_eq!(buf.headroom(), FRAME_HEADROOM - 3);
+    assert_eq!(buf[3..].as_ptr(), payload_at);
+}
+
+#[test]
+fn push_front_copies_when_shared_or_full() {
+    let mut buf = PacketBuf::from(b"payload".to_vec());
+    assert_eq!(buf.headroom(), 0);
+    buf.push_front(3).copy_from_slice(b"hdr");
+    assert_eq!(buf, b"hdrpayload");
+
+    let other = buf.clone();
+    buf.push_front(1).copy_from_slice(b"!");
+    assert_eq!(buf, b"!hdrpayload");
+    assert_eq!(other, b"hdrpayload");
+}
+
+#[test]
+fn slices_share_storage() {
+    let frame = PacketBuf::from(b"headerpayload".to_vec());
+    let mut payload = frame.slice(6..);
+    assert_eq!(payload, b"payload");
+    assert_eq!(payload.as_ptr(), frame[6..].as_ptr());
+    assert_eq!(payload.headroom(), 6);
+    payload.truncate(3);
+    assert_eq!(payload, b"pay");
+    payload.advance(1);
+    assert_eq!(payload, b"ay");
+    drop(frame);
+    assert_eq!(payload.into_vec(), b"ay");
+}
@@ -0,0 +1,171 @@
+// © 2022 <_@habnab.it>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+mod support;
+
+use std::time::Duration;
+
+use crabbletalk::{
+    aarp::PortId,
+    addr::{Appletalk, AppletalkNode, DdpType},
+};
+use support::{config, join_all, to, Conditions, Segment, SOCKET};
+
+#[tokio::test(start_paused = true)]
+async fn unicast() {
+    let segment = Segment::new(10);
+    let a = segment.join_random();
+    let b = segment.join_random();
+    let c = segment.join_random();
+    let sa = a.open_ddp_dynamic().await.unwrap();
+    let mut sb = b.open_ddp(SOCKET).await.unwrap();
+    let mut sc = c.open_ddp(SOCKET).await.unwrap();
+
+    sa.sendto(b"hello", to(sb.local_addr())).await.unwrap();
+    let got = sb.recv().await.unwrap();
+    assert_eq!(got.payload, b"hello");
+    assert_eq!(got.header.addr, sa.local_addr());
+    assert_eq!(got.header.socket, sa.local_socket());
+    assert_eq!(got.header.typ, DdpType::ATP);
+    assert_eq!(sc.recv_timeout(Duration::from_secs(1)).await.unwrap(), None);
+}
+
+#[tokio::test(start_paused = true)]
+async fn broadcast() {
+    let segment = Segment::new(11);
+    let a = segment.join_random();
+    let b = segment.join_random();
+    let c = segment.join_random();
+    let sa = a.open_ddp_dynamic().await.unwrap();
+    let mut sb = b.open_ddp(SOCKET).await.unwrap();
+    let mut sc = c.open_ddp(SOCKET).await.unwrap();
+    let everyone = Appletalk {
+        net: 0,
+        node: AppletalkNode::Broadcast,
+    };
+
+    assert!(sa.sendto(b"all", to(everyone)).await.is_err());
+    sa.set_broadcast(true);
+    sa.sendto(b"all", to(everyone)).await.unwrap();
+    assert_eq!(sb.recv().await.unwrap().payload, b"all");
+    assert_eq!(sc.recv().await.unwrap().payload, b"all");
+}
+
+#[tokio::test(start_paused = true)]
+async fn duplicates_arrive_twice() {
+    let segment = Segment::new(12);
+    let a = segment.join_random();
+    let b = segment.join_random();
+    let sa = a.open_ddp_dynamic().await.unwrap();
+    let mut sb = b.open_ddp(SOCKET).await.unwrap();
+
+    segment.set_conditions(Conditions {
+        duplicate: 1.0,
+        ..Default::default()
+    });
+    sa.sendto(b"again", to(sb.local_addr())).await.unwrap();
+    assert_eq!(sb.recv().await.unwrap().payload, b"again");
+    assert_eq!(sb.recv().await.unwrap().payload, b"again");
+    assert_eq!(sb.recv_timeout(Duration::from_secs(1)).await.unwrap(), None);
+}
+
+#[tokio::test(start_paused = true)]
+async fn delay_holds_datagrams_back() {
+    let segment = Segment::new(13);
+    let a = segment.join_random();
+    let b = segment.join_random();
+    let sa = a.open_ddp_dynamic().await.unwrap();
+    let mut sb = b.open_ddp(SOCKET).await.unwrap();
+    let dest = to(sb.local_addr());
+    // resolve first, so only the datagram itself is held back
+    sa.sendto(b"warm", dest).await.unwrap();
+    sb.recv().await.unwrap();
+
+    let delay = Duration::from_millis(50);
+    segment.set_conditions(Conditions {
+        delay,
+        ..Default::default()
+    });
+    let start = tokio::time::Instant::now();
+    sa.sendto(b"slow", dest).await.unwrap();
+    assert_eq!(sb.recv().await.unwrap().payload, b"slow");
+    assert!(start.elapsed() >= delay);
+}
+
+#[tokio::test(start_paused = true)]
+async fn partition_cuts_off_delivery() {
+    let segment = Segment::new(14);
+    let a = segment.join_random();
+    let b = segment.join_random();
+    let sa = a.open_ddp_dynamic().await.unwrap();
+    let mut sb = b.open_ddp(SOCKET).await.unwrap();
+    let dest = to(sb.local_addr());
+    sa.sendto(b"before", dest).await.unwrap();
+    assert_eq!(sb.recv().await.unwrap().payload, b"before");
+
+    segment.partition(&[&[0], &[1]]);
+    sa.sendto(b"during", dest).await.unwrap();
+    assert_eq!(sb.recv_timeout(Duration::from_secs(1)).await.unwrap(), None);
+
+    segment.heal();
+    sa.sendto(b"after", dest).await.unwrap();
+    assert_eq!(sb.recv().await.unwrap().payload, b"after");
+}
+
+#[tokio::test(start_paused = true)]
+async fn received_buffers_go_back_out() {
+    let segment = Segment::new(15);
+    let a = segment.join_random();
+    let b = segment.join_random();
+    let mut sa = a.open_ddp(SOCKET).await.unwrap();
+    let mut sb = b.open_ddp(SOCKET).await.unwrap();
+
+    sa.sendto(b"ping", to(sb.local_addr())).await.unwrap();
+    let got = sb.recv().await.unwrap();
+    sb.sendto_buf(got.payload, got.header).await.unwrap();
+    let back = sa.recv().await.unwrap();
+    assert_eq!(back.payload, b"ping");
+    assert_eq!(back.header.addr, sb.local_addr());
+}
+
+#[tokio::test(start_paused = true)]
+async fn sends_leave_by_the_sockets_port() {
+    let (hub, tap) = (Segment::new(16), Segment::new(17));
+    // two segments without a router, both in the startup range
+    let host = join_all([(&hub, config(1)), (&tap, config(2))]);
+    let _neighbour = hub.join_random();
+    let guest = tap.join_random();
+    let sh = host.open_ddp_dynamic_on(PortId(1)).await.unwrap();
+    let mut sg = guest.open_ddp(SOCKET).await.unwrap();
+
+    sh.sendto(b"hello", to(sg.local_addr())).await.unwrap();
+    let got = sg.recv().await.unwrap();
+    assert_eq!(got.payload, b"hello");
+    assert_eq!(Some(got.header.addr), host.address_on(PortId(1)).unwrap());
+}
+
+#[tokio::test(start_paused = true)]
+async fn each_port_has_its_own_sockets() {
+    let (hub, tap) = (Segment::new(18), Segment::new(19));
+    let host = join_all([(&hub, config(1)), (&tap, config(2))]);
+    let on_hub = hub.join_random();
+    let on_tap = tap.join_random();
+    let mut sh0 = host.open_ddp_on(PortId(0), SOCKET).await.unwrap();
+    let mut sh1 = host.open_ddp_on(PortId(1), SOCKET).await.unwrap();
+    let s_hub = on_hub.open_ddp_dynamic().await.unwrap();
+    let s_tap = on_tap.open_ddp_dynamic().await.unwrap();
+
+    s_hub.sendto(b"hub", to(sh0.local_addr())).await.unwrap();
+    s_tap.sendto(b"tap", to(sh1.local_addr())).await.unwrap();
+    assert_eq!(sh0.recv().await.unwrap().payload, b"hub");
+    assert_eq!(sh1.recv().await.unwrap().payload, b"tap");
+    assert_eq!(
+        sh0.recv_timeout(Duration::from_secs(1)).await.unwrap(),
+        None
+    );
+    assert_eq!(
+        sh1.recv_timeout(Duration::from_secs(1)).await.unwrap(),
+        None
+    );
+}
@@ -0,0 +1,196 @@
+// © 2022 <_@habnab.it>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use crabbletalk::{
+    addr::{Appletalk, AppletalkNode, AppletalkSocket, DdpType, Mac},
+    ddp::{Ddp, DDP_HEADER_LEN},
+    link::{pack_llap_ddp, unpack_llap_ddp, LlapType},
+    rtmp::RtmpData,
+    zip::NetInfoReply,
+};
+use packed_struct::PackedStruct;
+
+#[test]
+fn rtmp_data_extended() {
+    let data = [
+        0xFF, 0x01, 8, 0x2a, // router $ff01.$2a
+        0xFF, 0x00, 0x80, 0xFF, 0x0F, 0x82, // cable $ff00-$ff0f, distance 0
+        0x00, 0x10, 0x81, 0x00, 0x20, 0x82, // another network, one hop away
+    ];
+    let rtmp = RtmpData::parse(&data).unwrap();
+    assert_eq!(rtmp.router, Appletalk {
+        net: 0xFF01,
+        node: AppletalkNode::Node(0x2a),
+    });
+    assert_eq!(rtmp.cable_range, 0xFF00..=0xFF0F);
+}
+
+#[test]
+fn rtmp_data_non_extended() {
+    let data = [
+        0x00, 0x05, 8, 0x01, // router $0005.$01
+        0x00, 0x00, 0x82, // version triplet
+        0x00, 0x05, 0x00, // our network, distance 0
+        0x00, 0x10, 0x81, 0x00, 0x20, 0x82, // an extended network elsewhere
+    ];
+    let rtmp = RtmpData::parse(&data).unwrap();
+    assert_eq!(rtmp.router, Appletalk {
+        net: 5,
+        node: AppletalkNode::Node(1),
+    });
+    assert_eq!(rtmp.cable_range, 5..=5);
+}
+
+#[test]
+fn rtmp_data_bad_version() {
+    let data = [0xFF, 0x01, 8, 0x2a, 0xFF, 0x00, 0x80, 0xFF, 0x0F, 0x81];
+    assert!(RtmpData::parse(&data).is_err());
+}
+
+#[test]
+fn net_info_reply() {
+    let mut data = vec![
+        6,
+        0b0000_0000, // GetNetInfoReply, no flags
+        0xFF,
+        0x00,
+        0xFF,
+        0x0F, // cable $ff00-$ff0f
+    ];
+    data.extend_from_slice(b"\x04Dogs");
+    data.extend_from_slice(b"\x06\x09\x00\x07\x00\x00\x2a");
+    let reply = NetInfoReply::parse(&data).unwrap();
+    assert_eq!(reply.cable_range(), 0xFF00..=0xFF0F);
+    assert!(!reply.header.zone_invalid);
+    assert_eq!(reply.zone, b"Dogs");
+    assert_eq!(
+        reply.multicast,
+        Some(Mac {
+            oui: [0x09, 0x00, 0x07],
+            nic: [0x00, 0x00, 0x2a],
+        })
+    );
+    assert_eq!(reply.default_zone, None);
+    assert_eq!(reply.effective_zone(), b"Dogs");
+}
+
+#[test]
+fn net_info_reply_default_zone() {
+    let mut data = vec![
+        6,
+        0b1010_0000, // GetNetInfoReply, zone invalid, only one zone
+        0x00,
+        0x10,
+        0x00,
+        0x12,
+    ];
+    data.extend_from_slice(b"\x04Cats");
+    data.extend_from_slice(b"\x00"); // no multicast address given
+    data.extend_from_slice(b"\x08Everyone");
+    let reply = NetInfoReply::parse(&data).unwrap();
+    assert_eq!(reply.cable_range(), 0x10..=0x12);
+    assert!(reply.header.zone_invalid);
+    assert!(reply.header.only_one_zone);
+    assert!(!reply.header.use_broadcast);
+    assert_eq!(reply.zone, b"Cats");
+    assert_eq!(reply.multicast, None);
+    assert_eq!(reply.default_zone.as_deref(), Some(&b"Everyone"[..]));
+    assert_eq!(reply.effective_zone(), b"Everyone");
+}
+
+#[test]
+fn net_info_reply_truncated() {
+    let data = [6, 0, 0x00, 0x10, 0x00, 0x12, 0x04, b'C'];
+    assert!(NetInfoReply::parse(&data).is_err());
+}
+
+fn local_ddp(dest_net: u16, payload: &[u8]) -> Ddp {
+    Ddp {
+        _reserved: Default::default(),
+        hop_count: 0,
+        length: (DDP_HEADER_LEN + payload.len()) as u16,
+        checksum: 0,
+        dest_net,
+        src_net: 5,
+        dest_node: AppletalkNode::Node(3),
+        src_node: AppletalkNode::Node(4),
+        dest_socket: AppletalkSocket::ECHO,
+        src_socket: AppletalkSocket::Dynamic(200),
+        typ: DdpType::AEP,
+    }
+}
+
+#[test]
+fn ddp_long_short_long() {
+    let ddp = local_ddp(5, b"hello");
+    let short = ddp.to_short(5).unwrap();
+    assert_eq!(short.length, 5 + 5);
+    let long = short.to_long(5, ddp.dest_node, ddp.src_node);
+    assert_eq!(long.pack().unwrap(), ddp.pack().unwrap());
+}
+
+#[test]
+fn ddp_short_only_when_nothing_is_lost() {
+    assert!(local_ddp(7, b"hello").to_short(5).is_none());
+    let mut routed = local_ddp(5, b"hello");
+    routed.hop_count = 1;
+    assert!(routed.to_short(5).is_none());
+    let mut checksummed = local_ddp(5, b"hello");
+    checksummed.set_checksum_from(b"hello").unwrap();
+    assert!(checksummed.to_short(5).is_none());
+}
+
+#[test]
+fn llap_short_frame() {
+    let frame = [
+        3,
+        4,
+        1, // LLAP: to node 3 from node 4, short DDP
+        0x00,
+        5 + 5,
+        4,
+        200,
+        4, // short DDP: length, sockets, type
+        b'h',
+        b'e',
+        b'l',
+        b'l',
+        b'o',
+    ];
+    let (llap, ddp, payload) = unpack_llap_ddp(&frame, 5).unwrap();
+    assert_eq!(llap.typ, LlapType::DdpShort);
+    assert_eq!(ddp.destination(), Appletalk {
+        net: 5,
+        node: AppletalkNode::Node(3),
+    });
+    assert_eq!(ddp.source(), Appletalk {
+        net: 5,
+        node: AppletalkNode::Node(4),
+    });
+    assert_eq!(ddp.length as usize, DDP_HEADER_LEN + 5);
+    assert_eq!(ddp.dest_socket, AppletalkSocket::ECHO);
+    assert_eq!(ddp.typ, DdpType::AEP);
+    assert_eq!(payload, b"hello");
+}
+
+#[test]
+fn llap_prefers_short_on_net() {
+    let ddp = local_ddp(5, b"hello");
+    let frame = pack_llap_ddp(&ddp, b"hello", 5, true).unwrap();
+    assert_eq!(frame[2], LlapType::DdpShort as u8);
+    assert_eq!(frame.len(), 3 + 5 + 5);
+    let frame = pack_llap_ddp(&ddp, b"hello", 5, false).unwrap();
+    assert_eq!(frame[2], LlapType::DdpLong as u8);
+}
+
+#[test]
+fn llap_falls_back_to_long_across_nets() {
+    let ddp = local_ddp(7, b"hello");
+    let frame = pack_llap_ddp(&ddp, b"hello", 5, true).unwrap();
+    assert_eq!(frame[2], LlapType::DdpLong as u8);
+    assert_eq!(frame.len(), 3 + DDP_HEADER_LEN + 5);
+    let (_, back, payload) = unpack_llap_ddp(&frame, 5).unwrap();
+    assert_eq!(back.pack().unwrap(), ddp.pack().unwrap());
+    assert_eq!(payload, b"hello");
+}
@@ -0,0 +1,309 @@
+// © 2022 <_@habnab.it>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! The stack on its own, with no runtime: time only moves when a test says
+//! so, and everything random comes from a seed.
+
+mod support;
+
+use std::{
+    ops::RangeInclusive,
+    time::{Duration, Instant},
+};
+
+use crabbletalk::{
+    aarp::{AarpStack, AmtLimits, Output, PortId, StackConfig, StackEventKind},
+    addr::{
+        Appletalk, AppletalkNode, AppletalkSocket, DdpType, Ethertype, Mac,
+        APPLETALK_BROADCAST_MAC, APPLE_OUI, SNAP,
+    },
+    buf::{PacketBuf, FRAME_HEADROOM},
+    ddp::{Ddp, DdpOutbound, DDP_HEADER_LEN},
+    link::Elap,
+    rtmp::RTMP_VERSION,
+};
+use packed_struct::{PackedStructSlice, PrimitiveEnum};
+use rand::{rngs::StdRng, SeedableRng};
+use support::{config, HINT, SOCKET};
+
+const PORT: PortId = PortId(0);
+
+fn stack(config: StackConfig, start: Instant, seed: u64) -> AarpStack {
+    AarpStack::new([config], start, StdRng::seed_from_u64(seed)).unwrap()
+}
+
+fn drain(stack: &mut AarpStack) -> Vec<Output> {
+    std::iter::from_fn(|| stack.poll_output()).collect()
+}
+
+fn frames(outputs: &[Output]) -> Vec<PacketBuf> {
+    outputs
+        .iter()
+        .filter_map(|out| match out {
+            Output::Transmit { frame, .. } => Some(frame.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn events(outputs: &[Output]) -> Vec<StackEventKind> {
+    outputs
+        .iter()
+        .filter_map(|out| match out {
+            Output::Event(event) => Some(event.kind.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Step through `stack`'s deadlines until it accepts an address, returning
+/// it, when that happened, and how many frames it sent on the way.
+fn acquire(stack: &mut AarpStack, mut now: Instant) -> (Appletalk, Instant, usize) {
+    let mut sent = 0;
+    loop {
+        for out in drain(stack) {
+            match out {
+                Output::Transmit { .. } => sent += 1,
+                Output::Event(event) => {
+                    if let StackEventKind::AddressAcquired(addr) = event.kind {
+                        return (addr, now, sent);
+                    }
+                }
+                _ => (),
+            }
+        }
+        now = stack.next_deadline().expect("still probing");
+        stack.on_timer(now);
+    }
+}
+
+/// Hand every frame `from` wants sent to `to`.
+fn carry(from: &mut AarpStack, to: &mut AarpStack, now: Instant) -> Vec<Output> {
+    let outputs = drain(from);
+    for frame in frames(&outputs) {
+        to.process_ethernet(PORT, frame, now).unwrap();
+    }
+    outputs
+}
+
+fn datagram(dest: Appletalk, payload: &[u8]) -> DdpOutbound {
+    DdpOutbound {
+        header: Ddp {
+            _reserved: Default::default(),
+            hop_count: 0,
+            length: 0,
+            checksum: 0,
+            dest_net: dest.net,
+            src_net: 0,
+            dest_node: dest.node,
+            src_node: AppletalkNode::Unknown,
+            dest_socket: SOCKET,
+            src_socket: SOCKET,
+            typ: DdpType::ATP,
+        },
+        payload: PacketBuf::with_headroom(FRAME_HEADROOM, payload),
+        broadcast: false,
+        zone: None,
+    }
+}
+
+/// An RTMP Data broadcast from `router`, at `hw`, for an extended cable.
+fn rtmp(hw: Mac, router: Appletalk, cable: RangeInclusive<u16>) -> PacketBuf {
+    let (first, last) = cable.into_inner();
+    let mut payload = router.net.to_be_bytes().to_vec();
+    payload.extend([8, router.node.to_primitive()]);
+    payload.extend(first.to_be_bytes());
+    payload.push(0x80);
+    payload.extend(last.to_be_bytes());
+    payload.push(RTMP_VERSION);
+    let header = (
+        Elap {
+            destination: APPLETALK_BROADCAST_MAC,
+            source: hw,
+            length: (8 + DDP_HEADER_LEN + payload.len()) as u16,
+            dsap: SNAP,
+            ig: false,
+            ssap: SNAP,
+            cr: false,
+            control: 3,
+            oui: APPLE_OUI,
+            ethertype: Ethertype { protocol: 0x809B },
+        },
+        Ddp {
+            _reserved: Default::default(),
+            hop_count: 0,
+            length: (DDP_HEADER_LEN + payload.len()) as u16,
+            checksum: 0,
+            dest_net: 0,
+            src_net: router.net,
+            dest_node: AppletalkNode::Broadcast,
+            src_node: router.node,
+            dest_socket: AppletalkSocket::RTMP,
+            src_socket: AppletalkSocket::RTMP,
+            typ: DdpType::RTMP_DATA,
+        },
+    );
+    let mut frame = header.pack_to_vec().unwrap();
+    frame.extend(payload);
+    frame.into()
+}
+
+#[test]
+fn acquisition_follows_the_probe_schedule() {
+    let start = Instant::now();
+    let mut a = stack(config(1), start, 1);
+    let (addr, at, sent) = acquire(&mut a, start);
+    assert_eq!(sent, 10);
+    assert_eq!(at - start, 10 * Duration::from_millis(200));
+    assert_eq!(a.address(PORT).unwrap(), Some(addr));
+
+    let mut again = stack(config(1), start, 1);
+    assert_eq!(acquire(&mut again, start).0, addr);
+}
+
+#[test]
+fn a_probe_for_our_tentative_address_conflicts() {
+    let start = Instant::now();
+    let mut a = stack(config(1).address_hint(Some(HINT)), start, 1);
+    let mut b = stack(config(2).address_hint(Some(HINT)), start, 2);
+    a.on_timer(start);
+    drain(&mut b);
+
+    carry(&mut a, &mut b, start);
+    assert_eq!(events(&drain(&mut b)), [StackEventKind::Conflict {
+        addr: HINT,
+        from: config(1).hw(),
+    }]);
+}
+
+#[test]
+fn amt_changes_keep_their_place() {
+    let max_age = Duration::from_secs(10);
+    let limits = AmtLimits {
+        max_age,
+        ..Default::default()
+    };
+    let start = Instant::now();
+    let mut a = stack(config(1).amt_limits(limits), start, 1);
+    let mut b = stack(config(2), start, 2);
+    let (_, a_at, _) = acquire(&mut a, start);
+    let (b_addr, b_at, _) = acquire(&mut b, start);
+    let mut now = a_at.max(b_at);
+    a.bind(PORT, Some(SOCKET)).unwrap();
+    drain(&mut a);
+    drain(&mut b);
+
+    // resolve `b` once, so the next datagram goes straight out
+    let learned_at = now;
+    a.send_ddp(PORT, datagram(b_addr, b"one"), now);
+    carry(&mut a, &mut b, now);
+    carry(&mut b, &mut a, now);
+    assert!(
+        events(&drain(&mut a)).contains(&StackEventKind::AmtLearned {
+            atalk: b_addr,
+            hw: config(2).hw(),
+        })
+    );
+
+    now += max_age / 2;
+    let id = a.send_ddp(PORT, datagram(b_addr, b"two"), now);
+    a.on_timer(learned_at + max_age);
+    let outputs = drain(&mut a);
+    assert!(matches!(
+        &outputs[..],
+        [
+            Output::Transmit { .. },
+            Output::Sent { id: sent, result: Ok(()) },
+            Output::Event(event),
+            ..
+        ] if *sent == id && event.kind == StackEventKind::AmtForgotten {
+            atalk: b_addr,
+            hw: config(2).hw(),
+        }
+    ));
+}
+
+#[test]
+fn our_own_probes_coming_back_dont_conflict() {
+    let start = Instant::now();
+    let mut a = stack(config(1).address_hint(Some(HINT)), start, 1);
+    loop {
+        let outputs = drain(&mut a);
+        for frame in frames(&outputs) {
+            a.process_ethernet(PORT, frame, start).unwrap();
+        }
+        assert!(!events(&outputs)
+            .iter()
+            .any(|k| matches!(k, StackEventKind::Conflict { .. })));
+        if a.address(PORT).unwrap().is_some() {
+            break;
+        }
+        let now = a.next_deadline().unwrap();
+        a.on_timer(now);
+    }
+    assert_eq!(a.address(PORT).unwrap(), Some(HINT));
+}
+
+#[test]
+fn a_cable_range_without_our_address_sends_us_back_to_probing() {
+    let start = Instant::now();
+    let mut a = stack(config(1), start, 1);
+    let (addr, now, _) = acquire(&mut a, start);
+    let router = Appletalk {
+        net: 100,
+        node: AppletalkNode::Node(1),
+    };
+
+    a.process_ethernet(PORT, rtmp(config(9).hw(), router, 100..=100), now)
+        .unwrap();
+    assert!(
+        events(&drain(&mut a)).contains(&StackEventKind::AddressOutOfRange {
+            addr,
+            cable_range: 100..=100,
+        })
+    );
+    assert_eq!(a.address(PORT).unwrap(), None);
+
+    // the GetNetInfo query waits for the new address rather than running
+    // out while we probe
+    let (addr, _, _) = acquire(&mut a, now);
+    assert_eq!(addr.net, 100);
+    assert_eq!(a.snapshot().ports[0].stats.ddp_out, 2);
+}
+
+#[test]
+fn broadcasts_to_other_networks_go_to_the_router() {
+    let start = Instant::now();
+    let mut a = stack(config(1), start, 1);
+    let (addr, now, _) = acquire(&mut a, start);
+    let router_hw = config(9).hw();
+    let router = Appletalk {
+        net: addr.net,
+        node: AppletalkNode::Node(1),
+    };
+    a.process_ethernet(PORT, rtmp(router_hw, router, 0xFF00..=0xFFFE), now)
+        .unwrap();
+    drain(&mut a);
+
+    let mut to_mac = |dest| {
+        let out = DdpOutbound {
+            broadcast: true,
+            ..datagram(dest, b"everyone")
+        };
+        a.send_ddp(PORT, out, now);
+        let frames = frames(&drain(&mut a));
+        assert_eq!(frames.len(), 1);
+        Mac::unpack_from_slice(&frames[0][..6]).unwrap()
+    };
+    let elsewhere = Appletalk {
+        net: 100,
+        node: AppletalkNode::Broadcast,
+    };
+    let here = Appletalk {
+        net: 0,
+        node: AppletalkNode::Broadcast,
+    };
+    assert_eq!(to_mac(elsewhere), router_hw);
+    assert_eq!(to_mac(here), APPLETALK_BROADCAST_MAC);
+}
@@ -0,0 +1,239 @@
+// © 2022 <_@habnab.it>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! An Ethernet segment in memory, for tests which want several stacks
+//! talking to each other under tokio's paused clock.
+
+#![allow(dead_code)]
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crabbletalk::{
+    aarp::{AarpStackHandle, PortId, StackConfig, StackEvent, StackEventKind, TokioClock},
+    addr::{Appletalk, AppletalkNode, AppletalkSocket, DdpType, Mac, LAA_OUI},
+    buf::PacketBuf,
+    ddp::DdpHeader,
+    link::AppletalkPacket,
+};
+use packed_struct::PackedStructSlice;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+
+/// The socket tests send to.
+pub const SOCKET: AppletalkSocket = AppletalkSocket::Dynamic(200);
+
+/// The address stations are steered towards when a test wants them to
+/// collide.
+pub const HINT: Appletalk = Appletalk {
+    net: 0xFF10,
+    node: AppletalkNode::Node(42),
+};
+
+/// A default config for station `n`, whose MAC is its own.
+pub fn config(n: u8) -> StackConfig {
+    StackConfig::new(Mac {
+        oui: LAA_OUI,
+        nic: [0, 0, n],
+    })
+}
+
+/// `config(n)`, asking for `HINT` first.
+pub fn hinted(n: u8) -> StackConfig {
+    config(n).address_hint(Some(HINT))
+}
+
+/// An ATP datagram to `SOCKET` at `addr`.
+pub fn to(addr: Appletalk) -> DdpHeader {
+    DdpHeader {
+        addr,
+        socket: SOCKET,
+        typ: DdpType::ATP,
+    }
+}
+
+/// What happens to each frame on its way to each station.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Conditions {
+    /// Chance of a frame never arriving.
+    pub loss: f64,
+    /// Chance of a frame arriving twice.
+    pub duplicate: f64,
+    pub delay: Duration,
+}
+
+struct Station {
+    hw: Mac,
+    handle: AarpStackHandle,
+    port: PortId,
+    /// Stations only hear each other within the same group.
+    group: usize,
+}
+
+struct State {
+    seed: u64,
+    rng: StdRng,
+    conditions: Conditions,
+    stations: Vec<Station>,
+}
+
+/// A hub: every frame goes to every other station it's addressed to, subject
+/// to the segment's `Conditions`. Stations are numbered in the order they
+/// joined. Everything random, including the stacks' own choices, comes from
+/// the seed.
+#[derive(Clone)]
+pub struct Segment(Arc<Mutex<State>>);
+
+impl Segment {
+    pub fn new(seed: u64) -> Self {
+        Segment(Arc::new(Mutex::new(State {
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            conditions: Default::default(),
+            stations: Vec::new(),
+        })))
+    }
+
+    pub fn set_conditions(&self, conditions: Conditions) {
+        self.0.lock().unwrap().conditions = conditions;
+    }
+
+    /// Spawn a stack with its one port on this segment.
+    pub fn join(&self, config: StackConfig) -> AarpStackHandle {
+        join_all([(self, config)])
+    }
+
+    /// Plug `port` of a stack into the segment, carrying what it sends on
+    /// `link`.
+    fn attach(
+        &self,
+        hw: Mac,
+        handle: &AarpStackHandle,
+        port: PortId,
+        mut link: mpsc::Receiver<AppletalkPacket>,
+    ) {
+        let mut state = self.0.lock().unwrap();
+        let station = state.stations.len();
+        state.stations.push(Station {
+            hw,
+            handle: handle.clone(),
+            port,
+            group: 0,
+        });
+        let segment = self.clone();
+        tokio::spawn(async move {
+            while let Some(frame) = link.recv().await {
+                segment.carry(station, frame.0).await;
+            }
+        });
+    }
+
+    /// A station with a random MAC and otherwise default config.
+    pub fn join_random(&self) -> AarpStackHandle {
+        let hw = Mac::new_random_from(&mut self.0.lock().unwrap().rng);
+        self.join(StackConfig::new(hw))
+    }
+
+    /// Split the segment so that only stations in the same group hear each
+    /// other. Stations not mentioned end up together in a group of their own.
+    pub fn partition(&self, groups: &[&[usize]]) {
+        let mut state = self.0.lock().unwrap();
+        for station in &mut state.stations {
+            station.group = 0;
+        }
+        for (n, group) in groups.iter().enumerate() {
+            for &station in group.iter() {
+                state.stations[station].group = n + 1;
+            }
+        }
+    }
+
+    pub fn heal(&self) {
+        self.partition(&[]);
+    }
+
+    async fn carry(&self, from: usize, frame: PacketBuf) {
+        let dest = match Mac::unpack_from_slice(&frame[..6.min(frame.len())]) {
+            Ok(dest) => dest,
+            Err(_) => return,
+        };
+        let (deliveries, delay) = {
+            let mut state = self.0.lock().unwrap();
+            let State {
+                rng,
+                conditions,
+                stations,
+                ..
+            } = &mut *state;
+            let group = stations[from].group;
+            let mut deliveries = Vec::new();
+            for (n, station) in stations.iter().enumerate() {
+                let multicast = dest.oui[0] & 1 != 0;
+                if n == from || station.group != group || !(multicast || dest == station.hw) {
+                    continue;
+                }
+                if rng.gen_bool(conditions.loss) {
+                    continue;
+                }
+                let copies = if rng.gen_bool(conditions.duplicate) {
+                    2
+                } else {
+                    1
+                };
+                for _ in 0..copies {
+                    deliveries.push((station.handle.clone(), station.port));
+                }
+            }
+            (deliveries, conditions.delay)
+        };
+        for (handle, port) in deliveries {
+            // every station shares the one buffer, as they would the wire
+            if delay.is_zero() {
+                let _ = handle.process_ethernet_on(port, frame.clone()).await;
+            } else {
+                let frame = frame.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let _ = handle.process_ethernet_on(port, frame).await;
+                });
+            }
+        }
+    }
+}
+
+/// Spawn a stack with a port on each segment, numbered in order. Its
+/// randomness comes from the first segment's seed.
+pub fn join_all<'a>(
+    ports: impl IntoIterator<Item = (&'a Segment, StackConfig)>,
+) -> AarpStackHandle {
+    let (segments, configs): (Vec<_>, Vec<_>) = ports.into_iter().unzip();
+    let rng = {
+        let state = segments[0].0.lock().unwrap();
+        StdRng::seed_from_u64(state.seed.wrapping_add(1 + state.stations.len() as u64))
+    };
+    let hws = configs.iter().map(StackConfig::hw).collect::<Vec<_>>();
+    let (handle, links) =
+        AarpStackHandle::spawn_with(configs, TokioClock, rng).expect("valid config");
+    for (n, ((segment, hw), link)) in segments.into_iter().zip(hws).zip(links).enumerate() {
+        segment.attach(hw, &handle, PortId(n), link);
+    }
+    handle
+}
+
+/// Wait for the next event matching `f`, skipping the rest.
+pub async fn next_event(
+    events: &mut BroadcastStream<StackEvent>,
+    f: impl Fn(&StackEventKind) -> bool,
+) -> StackEventKind {
+    loop {
+        match events.next().await {
+            Some(Ok(event)) if f(&event.kind) => return event.kind,
+            Some(_) => continue,
+            None => panic!("stack went away"),
+        }
+    }
+}